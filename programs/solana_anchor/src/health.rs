@@ -0,0 +1,283 @@
+use {
+    crate::{math::*, LendingError, Obligation, Reserve},
+    anchor_lang::prelude::{AccountInfo, ProgramAccount, ProgramError, Pubkey},
+};
+
+/// Which side of the spot and TWAP prices a valuation takes
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PriceBasis {
+    /// collateral at the lower and debt at the higher price, gates borrows and withdrawals
+    Conservative,
+    /// collateral at the higher and debt at the lower price, so only a move seen by both spot and TWAP liquidates
+    Liquidation,
+}
+
+/// Values of an obligation across all of its reserves
+pub struct ObligationValues {
+    pub deposited_value : Decimal,
+    pub borrowed_value : Decimal,
+    pub allowed_borrow_value : Decimal,
+    pub unhealthy_borrow_value : Decimal,
+}
+
+/// Deserialize the reserves passed in `remaining_accounts`, they must all belong to `lending_market`
+pub fn load_obligation_reserves<'info>(
+    program_id : &Pubkey,
+    lending_market : &Pubkey,
+    accounts : &[AccountInfo<'info>],
+    ) -> Result<Vec<(Pubkey, Reserve)>, ProgramError> {
+    let mut reserves = Vec::with_capacity(accounts.len());
+    for account in accounts {
+        let reserve = ProgramAccount::<Reserve>::try_from(program_id, account)?.into_inner();
+        if reserve.lending_market != *lending_market {
+            return Err(LendingError::NotMatchLendingMarket.into());
+        }
+        reserves.push((*account.key, reserve));
+    }
+    Ok(reserves)
+}
+
+/// Replace a loaded reserve with the copy already being modified by the instruction
+pub fn override_reserve(reserves : &mut Vec<(Pubkey, Reserve)>, key : Pubkey, reserve : &Reserve) {
+    match reserves.iter_mut().find(|(reserve_key, _)| *reserve_key == key) {
+        Some(entry) => entry.1 = reserve.clone(),
+        None => reserves.push((key, reserve.clone())),
+    }
+}
+
+/// Whether any of the reserves is priced by hand, borrows, withdrawals and liquidations wait for the oracles to be back
+pub fn any_price_override(reserves : &[(Pubkey, Reserve)]) -> bool {
+    reserves.iter().any(|(_, reserve)| reserve.price_override)
+}
+
+/// Look up an obligation reserve, its market prices must have been refreshed in the current slot
+fn find_reserve<'a>(reserves : &'a [(Pubkey, Reserve)], key : &Pubkey, current_slot : u64) -> Result<&'a Reserve, ProgramError> {
+    let reserve = reserves.iter().find(|(reserve_key, _)| reserve_key == key).map(|(_, reserve)| reserve)
+        .ok_or(LendingError::ObligationReserveMissing)?;
+    if reserve.market_price_slot != current_slot {
+        return Err(LendingError::ReserveStale.into());
+    }
+    Ok(reserve)
+}
+
+/// Sum of `weight(reserve, value)` over every deposit of `obligation`
+fn weighted_deposit_value(
+    obligation : &Obligation,
+    reserves : &[(Pubkey, Reserve)],
+    current_slot : u64,
+    basis : PriceBasis,
+    weight : impl Fn(&Reserve, Decimal) -> Result<Decimal, ProgramError>,
+    ) -> Result<Decimal, ProgramError> {
+    let mut total = Decimal::zero();
+    for deposit in obligation.deposits.iter() {
+        let reserve = find_reserve(reserves, &deposit.reserve, current_slot)?;
+        total = total.try_add(weight(reserve, reserve.collateral_value(deposit.deposited_amount, basis)?)?)?;
+    }
+    Ok(total)
+}
+
+/// Value of the collateral deposited by `obligation`
+pub fn deposited_value(obligation : &Obligation, reserves : &[(Pubkey, Reserve)], current_slot : u64, basis : PriceBasis) -> Result<Decimal, ProgramError> {
+    weighted_deposit_value(obligation, reserves, current_slot, basis, |_, value| Ok(value))
+}
+
+/// Largest debt value the collateral of `obligation` can back, weighted by each reserve's loan-to-value
+pub fn allowed_borrow_value(obligation : &Obligation, reserves : &[(Pubkey, Reserve)], current_slot : u64, basis : PriceBasis) -> Result<Decimal, ProgramError> {
+    weighted_deposit_value(obligation, reserves, current_slot, basis, |reserve, value| {
+        value.try_mul_div(reserve.max_borrow_rate_numerator as u128, reserve.max_borrow_rate_denominator as u128)
+    })
+}
+
+/// Debt value above which `obligation` can be liquidated, weighted by each reserve's liquidation threshold
+pub fn unhealthy_borrow_value(obligation : &Obligation, reserves : &[(Pubkey, Reserve)], current_slot : u64, basis : PriceBasis) -> Result<Decimal, ProgramError> {
+    weighted_deposit_value(obligation, reserves, current_slot, basis, |reserve, value| {
+        value.try_mul_div(reserve.liquidation_threshold_numerator as u128, reserve.liquidation_threshold_denominator as u128)
+    })
+}
+
+/// Value of the debt of `obligation`, including interest accrued up to `current_slot`
+pub fn borrow_value(obligation : &Obligation, reserves : &[(Pubkey, Reserve)], current_slot : u64, basis : PriceBasis) -> Result<Decimal, ProgramError> {
+    let mut total = Decimal::zero();
+    for borrow in obligation.borrows.iter() {
+        let mut reserve = find_reserve(reserves, &borrow.reserve, current_slot)?.clone();
+        reserve.accrue_interest(current_slot)?;
+        total = total.try_add(reserve.liquidity_value(borrow.accrued_amount(reserve.cumulative_borrow_rate_wads)?.try_ceil_u64()?, basis)?)?;
+    }
+    Ok(total)
+}
+
+/// Every value of `obligation` at once
+pub fn obligation_values(obligation : &Obligation, reserves : &[(Pubkey, Reserve)], current_slot : u64, basis : PriceBasis) -> Result<ObligationValues, ProgramError> {
+    Ok(ObligationValues{
+        deposited_value : deposited_value(obligation, reserves, current_slot, basis)?,
+        borrowed_value : borrow_value(obligation, reserves, current_slot, basis)?,
+        allowed_borrow_value : allowed_borrow_value(obligation, reserves, current_slot, basis)?,
+        unhealthy_borrow_value : unhealthy_borrow_value(obligation, reserves, current_slot, basis)?,
+    })
+}
+
+/// Whether the debt of `obligation` exceeds its unhealthy borrow value under the liquidation prices
+pub fn is_liquidatable(obligation : &Obligation, reserves : &[(Pubkey, Reserve)], current_slot : u64) -> Result<bool, ProgramError> {
+    Ok(borrow_value(obligation, reserves, current_slot, PriceBasis::Liquidation)?
+        > unhealthy_borrow_value(obligation, reserves, current_slot, PriceBasis::Liquidation)?)
+}
+
+/// Collateral of `withdraw_reserve` that can leave `obligation` while its debt stays within the allowed borrow value
+pub fn max_withdraw_amount(
+    obligation : &Obligation,
+    reserves : &[(Pubkey, Reserve)],
+    withdraw_reserve : &Pubkey,
+    current_slot : u64,
+    ) -> Result<u64, ProgramError> {
+    let deposited_amount = obligation.deposits.iter()
+        .find(|deposit| deposit.reserve == *withdraw_reserve)
+        .map(|deposit| deposit.deposited_amount)
+        .ok_or(LendingError::ObligationReserveNotFound)?;
+    if obligation.borrows.is_empty() {
+        return Ok(deposited_amount);
+    }
+    let reserve = find_reserve(reserves, withdraw_reserve, current_slot)?;
+    if reserve.max_borrow_rate_numerator == 0 {
+        return Ok(deposited_amount);
+    }
+    let allowed_borrow_value = allowed_borrow_value(obligation, reserves, current_slot, PriceBasis::Conservative)?;
+    let borrow_value = borrow_value(obligation, reserves, current_slot, PriceBasis::Conservative)?;
+    if borrow_value >= allowed_borrow_value {
+        return Ok(0);
+    }
+    let withdraw_value = allowed_borrow_value.try_sub(borrow_value)?
+        .try_mul_div(reserve.max_borrow_rate_denominator as u128, reserve.max_borrow_rate_numerator as u128)?;
+    Ok(reserve.collateral_amount(withdraw_value, PriceBasis::Conservative)?.min(deposited_amount))
+}
+
+#[cfg(test)]
+mod tests {
+    use {
+        super::*,
+        crate::{rate_model::InterestRateConfig, twap::PriceTwap, ObligationCollateral, ObligationLiquidity, RESERVE_SIZE, WAD},
+        anchor_lang::prelude::AnchorDeserialize,
+    };
+
+    const SLOT : u64 = 1_000;
+
+    fn collateral_key() -> Pubkey {
+        Pubkey::new_from_array([1; 32])
+    }
+
+    fn liquidity_key() -> Pubkey {
+        Pubkey::new_from_array([2; 32])
+    }
+
+    /// Reserve refreshed at `SLOT` with whole-unit prices, 50% loan-to-value and 80% liquidation threshold
+    fn reserve(collateral_price : u128, collateral_twap : u128, liquidity_price : u128) -> Reserve {
+        let mut reserve = Reserve::deserialize(&mut &[0u8; RESERVE_SIZE][..]).unwrap();
+        reserve.collateral_market_price = collateral_price;
+        reserve.collateral_price_twap = PriceTwap{ price_wads : collateral_twap * WAD, last_price_wads : collateral_twap * WAD, last_update_slot : SLOT };
+        reserve.liquidity_market_price = liquidity_price;
+        reserve.liquidity_price_twap = PriceTwap{ price_wads : liquidity_price * WAD, last_price_wads : liquidity_price * WAD, last_update_slot : SLOT };
+        reserve.max_borrow_rate_numerator = 50;
+        reserve.max_borrow_rate_denominator = 100;
+        reserve.liquidation_threshold_numerator = 80;
+        reserve.liquidation_threshold_denominator = 100;
+        reserve.cumulative_borrow_rate_wads = WAD;
+        reserve.last_update_slot = SLOT;
+        reserve.market_price_slot = SLOT;
+        reserve
+    }
+
+    /// 100 collateral tokens at 10 backing 150 liquidity tokens at 2
+    fn reserves(collateral_price : u128, collateral_twap : u128) -> Vec<(Pubkey, Reserve)> {
+        vec![
+            (collateral_key(), reserve(collateral_price, collateral_twap, 2)),
+            (liquidity_key(), reserve(10, 10, 2)),
+        ]
+    }
+
+    fn obligation(deposited_amount : u64, borrowed_amount : u64) -> Obligation {
+        Obligation{
+            lending_market : Pubkey::default(),
+            owner : Pubkey::default(),
+            deposits : vec![ObligationCollateral{ reserve : collateral_key(), deposited_amount }],
+            borrows : vec![ObligationLiquidity{ reserve : liquidity_key(), borrowed_amount_wads : borrowed_amount as u128 * WAD, cumulative_borrow_rate_wads : WAD }],
+            bump : 0,
+        }
+    }
+
+    #[test]
+    fn values_obligation_across_reserves() {
+        let values = obligation_values(&obligation(100, 150), &reserves(10, 10), SLOT, PriceBasis::Conservative).unwrap();
+        assert_eq!(values.deposited_value, Decimal::from(1_000u64));
+        assert_eq!(values.borrowed_value, Decimal::from(300u64));
+        assert_eq!(values.allowed_borrow_value, Decimal::from(500u64));
+        assert_eq!(values.unhealthy_borrow_value, Decimal::from(800u64));
+    }
+
+    #[test]
+    fn max_withdraw_leaves_exactly_the_allowed_borrow_value() {
+        let reserves = reserves(10, 10);
+        let amount = max_withdraw_amount(&obligation(100, 150), &reserves, &collateral_key(), SLOT).unwrap();
+        assert_eq!(amount, 40);
+        let remaining = obligation(100 - amount, 150);
+        assert_eq!(
+            allowed_borrow_value(&remaining, &reserves, SLOT, PriceBasis::Conservative),
+            borrow_value(&remaining, &reserves, SLOT, PriceBasis::Conservative),
+        );
+        assert_eq!(max_withdraw_amount(&remaining, &reserves, &collateral_key(), SLOT), Ok(0));
+    }
+
+    #[test]
+    fn max_withdraw_without_debt_is_the_whole_deposit() {
+        let mut obligation = obligation(100, 0);
+        obligation.borrows.clear();
+        assert_eq!(max_withdraw_amount(&obligation, &reserves(10, 10), &collateral_key(), SLOT), Ok(100));
+    }
+
+    #[test]
+    fn spot_dip_alone_does_not_liquidate() {
+        let obligation = obligation(100, 150);
+        let dipped = reserves(3, 10);
+        assert!(borrow_value(&obligation, &dipped, SLOT, PriceBasis::Conservative).unwrap()
+            > unhealthy_borrow_value(&obligation, &dipped, SLOT, PriceBasis::Conservative).unwrap());
+        assert_eq!(is_liquidatable(&obligation, &dipped, SLOT), Ok(false));
+        assert_eq!(is_liquidatable(&obligation, &reserves(3, 3), SLOT), Ok(true));
+    }
+
+    #[test]
+    fn rejects_reserves_not_refreshed_this_slot() {
+        assert_eq!(
+            borrow_value(&obligation(100, 150), &reserves(10, 10), SLOT + 1, PriceBasis::Conservative),
+            Err(LendingError::ReserveStale.into()),
+        );
+    }
+
+    #[test]
+    fn reserve_borrowed_amount_tracks_obligation_debt() {
+        let mut reserve = reserve(10, 10, 2);
+        reserve.interest_rate_config = InterestRateConfig{ optimal_utilization_rate : 80, min_borrow_rate : 5, optimal_borrow_rate : 30, max_borrow_rate : 250 };
+        reserve.borrowed_amount_wads = 1_000_003 * WAD;
+        reserve.available_liquidity = 1_000;
+        let mut obligation = obligation(100, 1_000_003);
+        let borrow = &mut obligation.borrows[0];
+
+        // the obligation is only touched every few slots while the reserve accrues every slot
+        for slot in SLOT + 1..SLOT + 20_000 {
+            reserve.accrue_interest(slot).unwrap();
+            if slot % 7 == 0 {
+                borrow.accrue_interest(reserve.cumulative_borrow_rate_wads).unwrap();
+            }
+        }
+        borrow.accrue_interest(reserve.cumulative_borrow_rate_wads).unwrap();
+        let debt = borrow.borrowed_amount().unwrap();
+        let total = reserve.borrowed_amount().unwrap();
+        assert!(total > Decimal::from(1_000_003u64));
+        assert!(total.max(debt).try_sub(total.min(debt)).unwrap() < Decimal::from_scaled_val(WAD / 1_000_000));
+        assert_eq!(reserve.total_liquidity(), debt.try_floor_u64());
+
+        let owed_amount = borrow.owed_amount().unwrap();
+        let settled_amount = borrow.settle(owed_amount).unwrap();
+        reserve.settle(settled_amount).unwrap();
+        assert_eq!(borrow.borrowed_amount_wads, 0);
+        assert!(reserve.borrowed_amount_wads < WAD / 1_000_000);
+        assert_eq!(reserve.total_liquidity(), Ok(0));
+    }
+}
@@ -1,14 +1,29 @@
 pub mod utils;
+pub mod health;
+pub mod math;
+pub mod rate_model;
+pub mod oracle;
+pub mod pyth;
+pub mod switchboard;
+pub mod twap;
 use borsh::{BorshDeserialize,BorshSerialize};
 use {
     crate::utils::*,
+    crate::rate_model::*,
+    crate::health::*,
+    crate::math::*,
+    crate::oracle::*,
+    crate::twap::*,
     anchor_lang::{
         prelude::*,
         solana_program::{
             program_pack::Pack,
             borsh::try_from_slice_unchecked,
-            clock::UnixTimestamp,
+            clock::{UnixTimestamp, DEFAULT_TICKS_PER_SECOND, DEFAULT_TICKS_PER_SLOT, SECONDS_PER_DAY},
             program_error::ProgramError,
+            program_option::COption,
+            program::invoke,
+            instruction::{AccountMeta as InstructionAccountMeta, Instruction},
         },
         Key,
     },
@@ -16,9 +31,28 @@ use {
 };
 declare_id!("Fg6PaFpoGXkYsidMpWTK6W2BeZ7FEfcYkg476zPFsLnS");
 
-pub const LENDING_MARKET_SIZE : usize = 32+32;
-pub const RESERVE_SIZE : usize = 1+32+32+32+32+32+32+8+8+8+8+16+1+16+1+20;
-pub const OBLIGATION_SIZE : usize = 32+32+8+8+1;
+pub const LENDING_MARKET_SIZE : usize = 32+32+32+32+32+32+32+32+32+32+8;
+pub const RESERVE_SIZE : usize = 1+32+32+32+32+32+8+16+8+8+16+1+16+1+8+16+8+INTEREST_RATE_CONFIG_SIZE+1+1+8+8+32+8+1+1+2+8+1+ORACLE_SET_SIZE+ORACLE_SET_SIZE+PRICE_TWAP_SIZE+PRICE_TWAP_SIZE+1+8+2+1+8+8+20;
+pub const RESERVE_CONFIG_SIZE : usize = 8+8+8+8+INTEREST_RATE_CONFIG_SIZE+1+1+2+1+2+1+8+8;
+pub const PENDING_RESERVE_CONFIG_SIZE : usize = 32+RESERVE_CONFIG_SIZE+8+1;
+pub const PENDING_RESERVE_ORACLES_SIZE : usize = 32+ORACLE_SET_SIZE+ORACLE_SET_SIZE+8+1;
+pub const OBLIGATION_SIZE : usize = 32+32+4+MAX_OBLIGATION_DEPOSITS*OBLIGATION_COLLATERAL_SIZE+4+MAX_OBLIGATION_BORROWS*OBLIGATION_LIQUIDITY_SIZE+1;
+pub const OBLIGATION_COLLATERAL_SIZE : usize = 32+8;
+pub const OBLIGATION_LIQUIDITY_SIZE : usize = 32+16+16;
+pub const MAX_OBLIGATION_DEPOSITS : usize = 5;
+pub const MAX_OBLIGATION_BORROWS : usize = 5;
+
+/// Denominator of the fees configured in basis points
+pub const BPS_DENOMINATOR : u64 = 10_000;
+/// Furthest a manually pinned price may sit from the stored TWAP
+pub const MAX_PRICE_OVERRIDE_DEVIATION_BPS : u64 = 1_000;
+/// First byte of the instruction sent to a flash loan receiver program
+pub const FLASH_LOAN_RECEIVE_TAG : u8 = 0;
+
+/// Scale of the fixed-point values stored with a `_wads` suffix
+pub const WAD : u128 = 1_000_000_000_000_000_000;
+/// Approximate number of slots produced per year
+pub const SLOTS_PER_YEAR : u64 = DEFAULT_TICKS_PER_SECOND * SECONDS_PER_DAY * 365 / DEFAULT_TICKS_PER_SLOT;
 
 #[program]
 pub mod solana_anchor {
@@ -26,33 +60,137 @@ pub mod solana_anchor {
 
     pub fn init_lending_market(
         ctx : Context<InitLendingMarket>,
+        _config_delay : i64,
         ) -> ProgramResult {
         msg!("Processing initialize_lending_market");
+        if _config_delay < 0 {
+            return Err(LendingError::InvalidConfigDelay.into());
+        }
         let lending_market = &mut ctx.accounts.lending_market;
+        lending_market.config_delay = _config_delay;
         lending_market.owner = ctx.accounts.authority.key();
+        lending_market.pending_owner = Pubkey::default();
+        lending_market.risk_admin = ctx.accounts.authority.key();
+        lending_market.oracle_admin = ctx.accounts.authority.key();
+        lending_market.pause_guardian = ctx.accounts.authority.key();
+        lending_market.treasury = ctx.accounts.authority.key();
+        lending_market.fee_receiver = ctx.accounts.authority.key();
         lending_market.oracle_program_id = *ctx.accounts.oracle_program_id.key;
+        lending_market.pyth_program_id = *ctx.accounts.pyth_program_id.key;
+        lending_market.switchboard_program_id = *ctx.accounts.switchboard_program_id.key;
+        emit!(LendingMarketInitialized{
+            lending_market : lending_market.key(),
+            owner : lending_market.owner,
+            config_delay : lending_market.config_delay,
+        });
         Ok(())
     }
 
+    /// Propose `new_owner`, ownership only moves once the new key accepts it
     pub fn set_lending_market_owner(
         ctx : Context<SetLendingMarketOwner>,
         ) -> ProgramResult {
         let lending_market = &mut ctx.accounts.lending_market;
-        lending_market.owner=*ctx.accounts.new_owner.key;
+        lending_market.pending_owner = *ctx.accounts.new_owner.key;
+        emit!(PendingOwnerChanged{
+            lending_market : lending_market.key(),
+            pending_owner : lending_market.pending_owner,
+        });
+        Ok(())
+    }
+
+    /// Complete a proposed transfer, any role or fee receiver still set to the previous owner moves with it
+    pub fn accept_lending_market_owner(
+        ctx : Context<AcceptLendingMarketOwner>,
+        ) -> ProgramResult {
+        let lending_market = &mut ctx.accounts.lending_market;
+        if lending_market.pending_owner == Pubkey::default() || lending_market.pending_owner != *ctx.accounts.new_owner.key {
+            return Err(LendingError::NotPendingOwner.into());
+        }
+        let lending_market_key = lending_market.key();
+        let market : &mut LendingMarket = lending_market;
+        let previous_owner = market.owner;
+        market.owner = market.pending_owner;
+        market.pending_owner = Pubkey::default();
+        // roles never handed to a separate key follow ownership, the previous owner keeps none of them
+        for role in [
+            &mut market.risk_admin,
+            &mut market.oracle_admin,
+            &mut market.pause_guardian,
+            &mut market.treasury,
+            &mut market.fee_receiver,
+        ].iter_mut() {
+            if **role == previous_owner {
+                **role = market.owner;
+            }
+        }
+        emit!(OwnerChanged{
+            lending_market : lending_market_key,
+            owner : market.owner,
+        });
+        emit!(RolesChanged{
+            lending_market : lending_market_key,
+            risk_admin : market.risk_admin,
+            oracle_admin : market.oracle_admin,
+            pause_guardian : market.pause_guardian,
+            treasury : market.treasury,
+        });
+        emit!(FeeReceiverChanged{
+            lending_market : lending_market_key,
+            fee_receiver : market.fee_receiver,
+        });
+        Ok(())
+    }
+
+    /// Hand the operational roles to separate keys, the owner keeps ownership and reserve listing
+    pub fn set_lending_market_roles(
+        ctx : Context<SetLendingMarketRoles>,
+        ) -> ProgramResult {
+        let lending_market = &mut ctx.accounts.lending_market;
+        lending_market.risk_admin = *ctx.accounts.risk_admin.key;
+        lending_market.oracle_admin = *ctx.accounts.oracle_admin.key;
+        lending_market.pause_guardian = *ctx.accounts.pause_guardian.key;
+        lending_market.treasury = *ctx.accounts.treasury.key;
+        emit!(RolesChanged{
+            lending_market : lending_market.key(),
+            risk_admin : lending_market.risk_admin,
+            oracle_admin : lending_market.oracle_admin,
+            pause_guardian : lending_market.pause_guardian,
+            treasury : lending_market.treasury,
+        });
+        Ok(())
+    }
+
+    pub fn cancel_lending_market_owner(
+        ctx : Context<CancelLendingMarketOwner>,
+        ) -> ProgramResult {
+        let lending_market = &mut ctx.accounts.lending_market;
+        lending_market.pending_owner = Pubkey::default();
+        emit!(PendingOwnerChanged{
+            lending_market : lending_market.key(),
+            pending_owner : lending_market.pending_owner,
+        });
         Ok(())
     }
 
+    #[allow(clippy::too_many_arguments)]
     pub fn init_reserve(
         ctx : Context<InitReserve>,
         _bump : u8,
-        _max_borrow_rate_numerator : u64,
-        _max_borrow_rate_denominator : u64,
+        _config : ReserveConfig,
+        _liquidity_oracles : OracleSet,
+        _collateral_oracles : OracleSet,
         ) -> ProgramResult {
         msg!("Processing init_reserve");
         
+        _config.validate()?;
+
         let lending_market = &mut ctx.accounts.lending_market;
         let liquidity_account : state::Account = state::Account::unpack_from_slice(&ctx.accounts.liquidity_account.data.borrow())?;
         let collateral_account : state::Account = state::Account::unpack_from_slice(&ctx.accounts.collateral_account.data.borrow())?;
+        let share_mint : state::Mint = state::Mint::unpack_from_slice(&ctx.accounts.share_mint.data.borrow())?;
+        let liquidity_mint : state::Mint = state::Mint::unpack_from_slice(&ctx.accounts.liquidity_mint.data.borrow())?;
+        let collateral_mint : state::Mint = state::Mint::unpack_from_slice(&ctx.accounts.collateral_mint.data.borrow())?;
 
         if liquidity_account.mint != *ctx.accounts.liquidity_mint.key {
             return Err(LendingError::NotMatchLiquidityMint.into());
@@ -62,9 +200,7 @@ pub mod solana_anchor {
             return Err(LendingError::NotMatchCollateralMint.into());
         }
 
-        if lending_market.oracle_program_id != *ctx.accounts.oracle_price.owner {
-            return Err(LendingError::InvalidOracleConfig.into());
-        }
+        check_oracle_accounts(lending_market, &_liquidity_oracles, &_collateral_oracles, ctx.remaining_accounts)?;
 
         if collateral_account.owner != ctx.accounts.reserve.key() {
             return Err(LendingError::NotMatchCollateralAccount.into())
@@ -74,31 +210,104 @@ pub mod solana_anchor {
             return Err(LendingError::NotMatchLiquidityAccount.into())
         }
 
+        if share_mint.mint_authority != COption::Some(ctx.accounts.reserve.key()) || share_mint.supply != 0 {
+            return Err(LendingError::NotMatchShareMint.into())
+        }
+
         let reserve = &mut ctx.accounts.reserve;
         reserve.lending_market = ctx.accounts.lending_market.key();
         reserve.liquidity_mint = *ctx.accounts.liquidity_mint.key;
+        reserve.liquidity_mint_decimals = liquidity_mint.decimals;
         reserve.liquidity_account = *ctx.accounts.liquidity_account.key;
-        reserve.liquidity_oracle = *ctx.accounts.oracle_price.key;
+        reserve.liquidity_oracles = _liquidity_oracles;
+        reserve.collateral_oracles = _collateral_oracles;
+        reserve.price_override = false;
         reserve.collateral_mint = *ctx.accounts.collateral_mint.key;
+        reserve.collateral_mint_decimals = collateral_mint.decimals;
         reserve.collateral_account = *ctx.accounts.collateral_account.key;
-        reserve.max_borrow_rate_numerator = _max_borrow_rate_numerator;
-        reserve.max_borrow_rate_denominator = _max_borrow_rate_denominator;
-        reserve.total_liquidity = 0;
+        reserve.share_mint = *ctx.accounts.share_mint.key;
+        reserve.share_mint_total_supply = 0;
+        reserve.apply_config(&_config);
+        reserve.borrowed_amount_wads = 0;
         reserve.total_collateral = 0;
-        reserve.is_live = false;
+        reserve.status = ReserveStatus::Frozen;
+        reserve.available_liquidity = 0;
+        reserve.accumulated_protocol_fees = 0;
+        reserve.liquidity_price_twap = PriceTwap::default();
+        reserve.collateral_price_twap = PriceTwap::default();
+        reserve.cumulative_borrow_rate_wads = WAD;
+        reserve.last_update_slot = Clock::get()?.slot;
         reserve.bump = _bump;
+        emit!(ReserveInitialized{
+            lending_market : reserve.lending_market,
+            reserve : reserve.key(),
+            liquidity_mint : reserve.liquidity_mint,
+            collateral_mint : reserve.collateral_mint,
+            config : _config,
+        });
+        Ok(())
+    }
+
+    pub fn refresh_reserve(
+        ctx : Context<RefreshReserve>,
+        ) -> ProgramResult {
+        let reserve = &mut ctx.accounts.reserve;
+        // remaining accounts hold the liquidity oracles followed by the collateral oracles
+        let liquidity_oracle_count = reserve.liquidity_oracles.oracles.len();
+        if ctx.remaining_accounts.len() != liquidity_oracle_count + reserve.collateral_oracles.oracles.len() {
+            return Err(LendingError::InvalidOracleConfig.into());
+        }
+        let (liquidity_oracle_prices, collateral_oracle_prices) = ctx.remaining_accounts.split_at(liquidity_oracle_count);
+        let clock = Clock::get()?;
+        // prices pinned by set_market_price are kept until the override is lifted
+        if !reserve.price_override {
+            let (liquidity_price, liquidity_decimals) = reserve.liquidity_oracles.read_price(liquidity_oracle_prices, &clock)?;
+            let (collateral_price, collateral_decimals) = reserve.collateral_oracles.read_price(collateral_oracle_prices, &clock)?;
+            reserve.liquidity_market_price = liquidity_price;
+            reserve.liquidity_market_price_decimals = liquidity_decimals;
+            reserve.collateral_market_price = collateral_price;
+            reserve.collateral_market_price_decimals = collateral_decimals;
+            // only oracle prices feed the averages that bound set_market_price
+            reserve.update_price_twaps(clock.slot)?;
+        }
+        reserve.market_price_slot = clock.slot;
+        accrue_reserve_interest(reserve, clock.slot)?;
+        emit!(PriceUpdated{
+            reserve : reserve.key(),
+            liquidity_market_price : reserve.liquidity_market_price,
+            liquidity_market_price_decimals : reserve.liquidity_market_price_decimals,
+            collateral_market_price : reserve.collateral_market_price,
+            collateral_market_price_decimals : reserve.collateral_market_price_decimals,
+            slot : clock.slot,
+        });
         Ok(())
     }
 
     pub fn reserve_live_control(
         ctx : Context<ReserveLiveControl>,
-        is_live : bool
+        status : ReserveStatus,
         ) -> ProgramResult {
         let reserve = &mut ctx.accounts.reserve;
-        if reserve.lending_market != ctx.accounts.lending_market.key() {
+        let lending_market = &ctx.accounts.lending_market;
+        if reserve.lending_market != lending_market.key() {
             return Err(LendingError::NotMatchLendingMarket.into());
         }
-        reserve.is_live = is_live;
+        // the pause guardian may only tighten the status, lifting a pause needs the owner
+        let authority = *ctx.accounts.authority.key;
+        if authority != lending_market.owner {
+            if authority != lending_market.pause_guardian {
+                return Err(LendingError::Unauthorized.into());
+            }
+            if !status.is_at_least_as_restrictive_as(&reserve.status) {
+                return Err(LendingError::Unauthorized.into());
+            }
+        }
+        reserve.status = status;
+        emit!(ReserveStatusChanged{
+            reserve : reserve.key(),
+            authority,
+            status,
+        });
         Ok(())
     }
 
@@ -108,10 +317,15 @@ pub mod solana_anchor {
         ) -> ProgramResult {
         let obligation = &mut ctx.accounts.obligation;
         obligation.owner = *ctx.accounts.owner.key;
-        obligation.reserve = *ctx.accounts.reserve.key;
-        obligation.input_amount = 0;
-        obligation.output_amount = 0;
+        obligation.lending_market = ctx.accounts.lending_market.key();
+        obligation.deposits = Vec::new();
+        obligation.borrows = Vec::new();
         obligation.bump = _bump;
+        emit!(ObligationInitialized{
+            lending_market : obligation.lending_market,
+            obligation : obligation.key(),
+            owner : obligation.owner,
+        });
         Ok(())
     }
 
@@ -120,10 +334,13 @@ pub mod solana_anchor {
         collateral_amount : u64,
         ) -> ProgramResult {
         let obligation = &mut ctx.accounts.obligation;        
-        if obligation.reserve != ctx.accounts.reserve.key() {
-            return Err(LendingError::NotMatchReserveAddress.into());
-        }
         let reserve = &mut ctx.accounts.reserve;
+        if !reserve.status.allows_deposit() {
+            return Err(LendingError::ReservePaused.into());
+        }
+        if reserve.lending_market != obligation.lending_market {
+            return Err(LendingError::NotMatchLendingMarket.into());
+        }
         if reserve.collateral_account != *ctx.accounts.dest_collateral.key {
             return Err(LendingError::NotMatchCollateralAccount.into());
         }
@@ -135,6 +352,10 @@ pub mod solana_anchor {
         if dest_collateral.mint != reserve.collateral_mint {
             return Err(LendingError::NotMatchLiquidityMint.into());
         }
+        let total_collateral = reserve.total_collateral.try_add(collateral_amount)?;
+        if total_collateral > reserve.deposit_limit {
+            return Err(LendingError::DepositLimitExceeded.into());
+        }
         ////////////////////////////////////////////////////
         spl_token_transfer_without_seed(
             TokenTransferParamsWithoutSeed{
@@ -145,41 +366,43 @@ pub mod solana_anchor {
                 amount : collateral_amount,
             }
         )?;
-        obligation.input_amount= obligation.input_amount + collateral_amount;
-        reserve.total_collateral = reserve.total_collateral + collateral_amount;
+        let deposit = obligation.find_or_add_deposit(reserve.key())?;
+        deposit.deposited_amount = deposit.deposited_amount.try_add(collateral_amount)?;
+        let deposited_amount = deposit.deposited_amount;
+        reserve.total_collateral = total_collateral;
+        emit!(CollateralDeposited{
+            reserve : reserve.key(),
+            obligation : obligation.key(),
+            owner : obligation.owner,
+            amount : collateral_amount,
+            deposited_amount,
+            total_collateral,
+        });
         Ok(())
     }
 
-    pub fn withdraw_collateral(
-        ctx : Context<WithdrawCollateral>,
+    pub fn withdraw_collateral<'info>(
+        ctx : Context<'_, '_, '_, 'info, WithdrawCollateral<'info>>,
         collateral_amount : u64,
         ) -> ProgramResult {
+        let reserve_account_info = ctx.accounts.reserve.to_account_info().clone();
         let obligation = &mut ctx.accounts.obligation;
-        let reserve_account_info = ctx.accounts.reserve.to_account_info().clone();        
-        if obligation.reserve != ctx.accounts.reserve.key() {
-            return Err(LendingError::NotMatchReserveAddress.into());
-        }
         let reserve = &mut ctx.accounts.reserve;
+        if !reserve.status.allows_withdraw() {
+            return Err(LendingError::ReservePaused.into());
+        }
         if reserve.lending_market != *ctx.accounts.lending_market.key {
             return Err(LendingError::NotMatchLendingMarket.into());
         }
+        if obligation.lending_market != *ctx.accounts.lending_market.key {
+            return Err(LendingError::NotMatchLendingMarket.into());
+        }
 
         if reserve.collateral_account != *ctx.accounts.source_collateral.key {
             return Err(LendingError::NotMatchCollateralAccount.into());
         }
-        if reserve.liquidity_oracle != *ctx.accounts.oracle_price.key {
-            return Err(LendingError::InvalidOracleConfig.into());
-        }
         let source_collateral : state::Account = state::Account::unpack_from_slice(&ctx.accounts.source_collateral.data.borrow())?;
         let dest_collateral : state::Account = state::Account::unpack_from_slice(&ctx.accounts.dest_collateral.data.borrow())?;
-        let liquidity_mint : state::Mint = state::Mint::unpack_from_slice(&ctx.accounts.liquidity_mint.data.borrow())?;
-        let collateral_mint : state::Mint = state::Mint::unpack_from_slice(&ctx.accounts.collateral_mint.data.borrow())?;
-        if reserve.collateral_mint != *ctx.accounts.collateral_mint.key{
-            return Err(LendingError::NotMatchCollateralMint.into());
-        }
-        if reserve.liquidity_mint != *ctx.accounts.liquidity_mint.key {
-            return Err(LendingError::NotMatchCollateralMint.into());
-        }
         if source_collateral.mint != reserve.collateral_mint {
             return Err(LendingError::NotMatchCollateralMint.into());
         }
@@ -188,31 +411,27 @@ pub mod solana_anchor {
         }
         ////////////////////////////////////////////////////
 
+        let current_slot = Clock::get()?.slot;
+        accrue_reserve_interest(reserve, current_slot)?;
+
         //Can I borrow?
         if collateral_amount > source_collateral.amount {
             return Err(LendingError::NotEnoughCollateral.into());
         }
-        let mut real_amount = collateral_amount;
-        if collateral_amount > obligation.input_amount {
-            real_amount = obligation.input_amount;
-        }
-
-        if (
-            obligation.output_amount as u128
-                * reserve.liquidity_market_price as u128 
-                * reserve.max_borrow_rate_denominator as u128 
-                / 10u128.pow((liquidity_mint.decimals + reserve.liquidity_market_price_decimals) as u32)
-            )
-            >
-           (
-            (obligation.input_amount - real_amount) as u128
-                * reserve.collateral_market_price as u128
-                * reserve.max_borrow_rate_numerator as u128 
-                / 10u128.pow((collateral_mint.decimals + reserve.collateral_market_price_decimals) as u32)
-            ) 
-           {
+        let mut reserves = load_obligation_reserves(ctx.program_id, &obligation.lending_market, ctx.remaining_accounts)?;
+        override_reserve(&mut reserves, reserve.key(), reserve);
+        if any_price_override(&reserves) {
+            return Err(LendingError::PriceOverrideActive.into());
+        }
+        let deposit = obligation.find_deposit_mut(&reserve.key())?;
+        let real_amount = collateral_amount.min(deposit.deposited_amount);
+        if real_amount > max_withdraw_amount(obligation, &reserves, &reserve.key(), current_slot)? {
             return Err(LendingError::InvalidBorrowRate.into());
         }
+        let deposit = obligation.find_deposit_mut(&reserve.key())?;
+        deposit.deposited_amount = deposit.deposited_amount.try_sub(real_amount)?;
+        let deposited_amount = deposit.deposited_amount;
+        obligation.remove_empty_positions();
 
         let lending_seeds = &[
             ctx.accounts.lending_market.key.as_ref(),
@@ -231,40 +450,39 @@ pub mod solana_anchor {
                 amount : real_amount,
             }
         )?;
-        obligation.input_amount= obligation.input_amount - real_amount;
-        reserve.total_collateral = reserve.total_collateral - real_amount;
+        reserve.total_collateral = reserve.total_collateral.try_sub(real_amount)?;
+        emit!(CollateralWithdrawn{
+            reserve : reserve.key(),
+            obligation : obligation.key(),
+            owner : obligation.owner,
+            amount : real_amount,
+            deposited_amount,
+            total_collateral : reserve.total_collateral,
+        });
         Ok(())
     }
 
-    pub fn borrow_liquidity(
-        ctx : Context<BorrowLiquidity>,
+    pub fn borrow_liquidity<'info>(
+        ctx : Context<'_, '_, '_, 'info, BorrowLiquidity<'info>>,
         liquidity_amount : u64,
         ) -> ProgramResult {
         let reserve_account_info = ctx.accounts.reserve.to_account_info().clone(); 
         let obligation = &mut ctx.accounts.obligation;
-        if obligation.reserve != ctx.accounts.reserve.key() {
-            return Err(LendingError::NotMatchReserveAddress.into());
-        }
         let reserve = &mut ctx.accounts.reserve;
+        if !reserve.status.allows_borrow() {
+            return Err(LendingError::ReservePaused.into());
+        }
         if reserve.lending_market != *ctx.accounts.lending_market.key {
             return Err(LendingError::NotMatchLendingMarket.into());
         }
+        if obligation.lending_market != *ctx.accounts.lending_market.key {
+            return Err(LendingError::NotMatchLendingMarket.into());
+        }
         if reserve.liquidity_account != *ctx.accounts.source_liquidity.key {
             return Err(LendingError::NotMatchLiquidityAccount.into());
         }
-        if reserve.liquidity_oracle != *ctx.accounts.oracle_price.key {
-            return Err(LendingError::InvalidOracleConfig.into());
-        }
         let source_liquidity : state::Account = state::Account::unpack_from_slice(&ctx.accounts.source_liquidity.data.borrow())?;
         let dest_liquidity : state::Account = state::Account::unpack_from_slice(&ctx.accounts.dest_liquidity.data.borrow())?;
-        let liquidity_mint : state::Mint = state::Mint::unpack_from_slice(&ctx.accounts.liquidity_mint.data.borrow())?;
-        let collateral_mint : state::Mint = state::Mint::unpack_from_slice(&ctx.accounts.collateral_mint.data.borrow())?;
-        if reserve.collateral_mint != *ctx.accounts.collateral_mint.key{
-            return Err(LendingError::NotMatchCollateralMint.into());
-        }
-        if reserve.liquidity_mint != *ctx.accounts.liquidity_mint.key {
-            return Err(LendingError::NotMatchLiquidityMint.into());
-        }
         if source_liquidity.mint != reserve.liquidity_mint {
             return Err(LendingError::NotMatchLiquidityMint.into());
         }
@@ -273,25 +491,46 @@ pub mod solana_anchor {
         }
         ////////////////////////////////////////////////
 
+        let current_slot = Clock::get()?.slot;
+        accrue_reserve_interest(reserve, current_slot)?;
+
+        // a trailing token account among the remaining accounts is the host fee receiver
+        let (reserve_accounts, host_fee_account) = match ctx.remaining_accounts.split_last() {
+            Some((last, rest)) if *last.owner == spl_token::id() => (rest, Some(last)),
+            _ => (ctx.remaining_accounts, None),
+        };
+        let (borrow_fee, mut host_fee) = reserve.borrow_fees(liquidity_amount)?;
+        match host_fee_account {
+            Some(host_fee_account) => {
+                let host_liquidity : state::Account = state::Account::unpack_from_slice(&host_fee_account.data.borrow())?;
+                if host_liquidity.mint != reserve.liquidity_mint {
+                    return Err(LendingError::NotMatchLiquidityMint.into());
+                }
+            },
+            None => host_fee = 0,
+        }
+
         //Can I borrow?
-        if liquidity_amount > source_liquidity.amount {
+        if liquidity_amount.try_add(host_fee)? > reserve.available_liquidity {
             return Err(LendingError::NotEnoughLiquidity.into());
         }
 
-        if (
-            (obligation.output_amount + liquidity_amount) as u128
-                * reserve.liquidity_market_price as u128 
-                * reserve.max_borrow_rate_denominator as u128 
-                / 10u128.pow((liquidity_mint.decimals + reserve.liquidity_market_price_decimals) as u32)
-            )
-            >
-           (
-            obligation.input_amount as u128
-                * reserve.collateral_market_price as u128
-                * reserve.max_borrow_rate_numerator as u128 
-                / 10u128.pow((collateral_mint.decimals + reserve.collateral_market_price_decimals) as u32)
-            ) 
-           {
+        // the origination fee is added to the debt, the borrower receives the full amount
+        let borrow_amount = liquidity_amount.try_add(borrow_fee)?;
+        if reserve.total_liquidity()?.try_add(borrow_amount)? > reserve.borrow_limit {
+            return Err(LendingError::BorrowLimitExceeded.into());
+        }
+        let borrow = obligation.find_or_add_borrow(reserve.key(), reserve.cumulative_borrow_rate_wads)?;
+        borrow.accrue_interest(reserve.cumulative_borrow_rate_wads)?;
+        borrow.borrowed_amount_wads = borrow.borrowed_amount()?.try_add(Decimal::from(borrow_amount))?.to_scaled_val();
+        let borrowed_amount = borrow.owed_amount()?;
+
+        let mut reserves = load_obligation_reserves(ctx.program_id, &obligation.lending_market, reserve_accounts)?;
+        override_reserve(&mut reserves, reserve.key(), reserve);
+        if any_price_override(&reserves) {
+            return Err(LendingError::PriceOverrideActive.into());
+        }
+        if borrow_value(obligation, &reserves, current_slot, PriceBasis::Conservative)? > allowed_borrow_value(obligation, &reserves, current_slot, PriceBasis::Conservative)? {
             return Err(LendingError::InvalidBorrowRate.into());
         }
 
@@ -306,14 +545,39 @@ pub mod solana_anchor {
             TokenTransferParams{
                 source : ctx.accounts.source_liquidity.clone(),
                 destination : ctx.accounts.dest_liquidity.clone(),
-                authority : reserve_account_info,
+                authority : reserve_account_info.clone(),
                 authority_signer_seeds : lending_seeds,
                 token_program : ctx.accounts.token_program.clone(),
                 amount : liquidity_amount,
             }
         )?;
-        obligation.output_amount = obligation.output_amount + liquidity_amount;
-        reserve.total_liquidity = reserve.total_liquidity + liquidity_amount;
+        if let Some(host_fee_account) = host_fee_account.filter(|_| host_fee > 0) {
+            spl_token_transfer(
+                TokenTransferParams{
+                    source : ctx.accounts.source_liquidity.clone(),
+                    destination : host_fee_account.clone(),
+                    authority : reserve_account_info,
+                    authority_signer_seeds : lending_seeds,
+                    token_program : ctx.accounts.token_program.clone(),
+                    amount : host_fee,
+                }
+            )?;
+        }
+        // the part of the fee not paid to the host is kept by the protocol
+        reserve.accumulated_protocol_fees = reserve.accumulated_protocol_fees.try_add(borrow_fee.try_sub(host_fee)?)?;
+        reserve.borrowed_amount_wads = reserve.borrowed_amount()?.try_add(Decimal::from(borrow_amount))?.to_scaled_val();
+        reserve.available_liquidity = reserve.available_liquidity.try_sub(liquidity_amount.try_add(host_fee)?)?;
+        emit!(LiquidityBorrowed{
+            reserve : reserve.key(),
+            obligation : obligation.key(),
+            owner : obligation.owner,
+            amount : liquidity_amount,
+            borrow_fee,
+            host_fee,
+            borrowed_amount,
+            total_liquidity : reserve.total_liquidity()?,
+            available_liquidity : reserve.available_liquidity,
+        });
         Ok(())
     }
 
@@ -322,10 +586,10 @@ pub mod solana_anchor {
         liquidity_amount : u64,
         ) -> ProgramResult{
         let obligation = &mut ctx.accounts.obligation;
-        if obligation.reserve != ctx.accounts.reserve.key() {
-            return Err(LendingError::NotMatchReserveAddress.into());
-        }
         let reserve = &mut ctx.accounts.reserve;
+        if reserve.lending_market != obligation.lending_market {
+            return Err(LendingError::NotMatchLendingMarket.into());
+        }
         if reserve.liquidity_account != *ctx.accounts.dest_liquidity.key {
             return Err(LendingError::NotMatchLiquidityAccount.into());
         }
@@ -338,9 +602,14 @@ pub mod solana_anchor {
             return Err(LendingError::NotMatchLiquidityMint.into());
         }
         ///////////////////////////////////////////////        
+        accrue_reserve_interest(reserve, Clock::get()?.slot)?;
+        let borrow = obligation.find_borrow_mut(&reserve.key())?;
+        borrow.accrue_interest(reserve.cumulative_borrow_rate_wads)?;
+
+        let owed_amount = borrow.owed_amount()?;
         let mut real_amount : u64 = liquidity_amount;
-        if real_amount > obligation.output_amount {
-            real_amount = obligation.output_amount;
+        if real_amount > owed_amount {
+            real_amount = owed_amount;
         }
         spl_token_transfer_without_seed(
             TokenTransferParamsWithoutSeed{
@@ -351,13 +620,167 @@ pub mod solana_anchor {
                 amount : real_amount,
             }
         )?;
-        obligation.output_amount = obligation.output_amount - real_amount;
-        reserve.total_liquidity = reserve.total_liquidity - real_amount;
+        let settled_amount = borrow.settle(real_amount)?;
+        let borrowed_amount = borrow.owed_amount()?;
+        obligation.remove_empty_positions();
+        reserve.settle(settled_amount)?;
+        reserve.available_liquidity = reserve.available_liquidity.try_add(real_amount)?;
+        emit!(LiquidityRepaid{
+            reserve : reserve.key(),
+            obligation : obligation.key(),
+            owner : obligation.owner,
+            amount : real_amount,
+            borrowed_amount,
+            total_liquidity : reserve.total_liquidity()?,
+            available_liquidity : reserve.available_liquidity,
+        });
+        Ok(())
+    }
+
+    pub fn liquidate_obligation<'info>(
+        ctx : Context<'_, '_, '_, 'info, LiquidateObligation<'info>>,
+        liquidity_amount : u64,
+        ) -> ProgramResult {
+        let repay_reserve_key = ctx.accounts.repay_reserve.key();
+        let withdraw_reserve_key = *ctx.accounts.withdraw_reserve.key;
+        // the same reserve may back both sides, only deserialize it once in that case
+        let mut withdraw_reserve = if withdraw_reserve_key == repay_reserve_key {
+            None
+        } else {
+            Some(ProgramAccount::<Reserve>::try_from(ctx.program_id, &ctx.accounts.withdraw_reserve)?)
+        };
+        let obligation = &mut ctx.accounts.obligation;
+        let repay_reserve = &mut ctx.accounts.repay_reserve;
+        if repay_reserve.lending_market != *ctx.accounts.lending_market.key {
+            return Err(LendingError::NotMatchLendingMarket.into());
+        }
+        if obligation.lending_market != *ctx.accounts.lending_market.key {
+            return Err(LendingError::NotMatchLendingMarket.into());
+        }
+        if repay_reserve.liquidity_account != *ctx.accounts.dest_liquidity.key {
+            return Err(LendingError::NotMatchLiquidityAccount.into());
+        }
+        let source_liquidity : state::Account = state::Account::unpack_from_slice(&ctx.accounts.source_liquidity.data.borrow())?;
+        let dest_collateral : state::Account = state::Account::unpack_from_slice(&ctx.accounts.dest_collateral.data.borrow())?;
+        if source_liquidity.mint != repay_reserve.liquidity_mint {
+            return Err(LendingError::NotMatchLiquidityMint.into());
+        }
+        let withdraw_reserve_data : &Reserve = withdraw_reserve.as_deref().unwrap_or(&**repay_reserve);
+        if withdraw_reserve_data.lending_market != *ctx.accounts.lending_market.key {
+            return Err(LendingError::NotMatchLendingMarket.into());
+        }
+        if withdraw_reserve_data.collateral_account != *ctx.accounts.source_collateral.key {
+            return Err(LendingError::NotMatchCollateralAccount.into());
+        }
+        if dest_collateral.mint != withdraw_reserve_data.collateral_mint {
+            return Err(LendingError::NotMatchCollateralMint.into());
+        }
+        ////////////////////////////////////////////////
+
+        let current_slot = Clock::get()?.slot;
+        accrue_reserve_interest(repay_reserve, current_slot)?;
+        let borrow = obligation.find_borrow_mut(&repay_reserve_key)?;
+        borrow.accrue_interest(repay_reserve.cumulative_borrow_rate_wads)?;
+        let borrowed_amount = borrow.owed_amount()?;
+        let deposited_amount = obligation.find_deposit_mut(&withdraw_reserve_key)?.deposited_amount;
+
+        //Can it be liquidated?
+        let mut reserves = load_obligation_reserves(ctx.program_id, &obligation.lending_market, ctx.remaining_accounts)?;
+        override_reserve(&mut reserves, repay_reserve_key, repay_reserve);
+        if any_price_override(&reserves) || matches!(withdraw_reserve.as_deref(), Some(reserve) if reserve.price_override) {
+            return Err(LendingError::PriceOverrideActive.into());
+        }
+        if !is_liquidatable(obligation, &reserves, current_slot)? {
+            return Err(LendingError::ObligationHealthy.into());
+        }
+
+        let mut max_repay_amount = Decimal::from(borrowed_amount)
+            .try_mul(Decimal::from_percent(repay_reserve.liquidation_close_factor))?
+            .try_floor_u64()?;
+        if max_repay_amount == 0 {
+            max_repay_amount = borrowed_amount;
+        }
+        let mut repay_amount = liquidity_amount.min(max_repay_amount);
+
+        let withdraw_reserve_data : &Reserve = withdraw_reserve.as_deref().unwrap_or(&**repay_reserve);
+        let mut withdraw_amount = liquidation_withdraw_amount(repay_amount, repay_reserve, withdraw_reserve_data)?;
+        if withdraw_amount > deposited_amount {
+            repay_amount = Decimal::from(repay_amount)
+                .try_mul_div(deposited_amount as u128, withdraw_amount as u128)?
+                .try_floor_u64()?;
+            withdraw_amount = deposited_amount;
+        }
+        if repay_amount == 0 || withdraw_amount == 0 {
+            return Err(LendingError::LiquidationTooSmall.into());
+        }
+
+        spl_token_transfer_without_seed(
+            TokenTransferParamsWithoutSeed{
+                source : ctx.accounts.source_liquidity.clone(),
+                destination : ctx.accounts.dest_liquidity.clone(),
+                authority : ctx.accounts.liquidator.clone(),
+                token_program : ctx.accounts.token_program.clone(),
+                amount : repay_amount,
+            }
+        )?;
+
+        let lending_seeds = &[
+            ctx.accounts.lending_market.key.as_ref(),
+            withdraw_reserve_data.collateral_mint.as_ref(),
+            withdraw_reserve_data.liquidity_mint.as_ref(),
+            &[withdraw_reserve_data.bump]
+        ];
+
+        spl_token_transfer(
+            TokenTransferParams{
+                source : ctx.accounts.source_collateral.clone(),
+                destination : ctx.accounts.dest_collateral.clone(),
+                authority : ctx.accounts.withdraw_reserve.clone(),
+                authority_signer_seeds : lending_seeds,
+                token_program : ctx.accounts.token_program.clone(),
+                amount : withdraw_amount,
+            }
+        )?;
+        let borrow = obligation.find_borrow_mut(&repay_reserve_key)?;
+        let settled_amount = borrow.settle(repay_amount)?;
+        let borrowed_amount = borrow.owed_amount()?;
+        let deposit = obligation.find_deposit_mut(&withdraw_reserve_key)?;
+        deposit.deposited_amount = deposit.deposited_amount.try_sub(withdraw_amount)?;
+        let deposited_amount = deposit.deposited_amount;
+        obligation.remove_empty_positions();
+        repay_reserve.settle(settled_amount)?;
+        repay_reserve.available_liquidity = repay_reserve.available_liquidity.try_add(repay_amount)?;
+        let withdraw_total_collateral = match withdraw_reserve.as_mut() {
+            Some(withdraw_reserve) => {
+                withdraw_reserve.total_collateral = withdraw_reserve.total_collateral.try_sub(withdraw_amount)?;
+                withdraw_reserve.exit(ctx.program_id)?;
+                withdraw_reserve.total_collateral
+            },
+            None => {
+                repay_reserve.total_collateral = repay_reserve.total_collateral.try_sub(withdraw_amount)?;
+                repay_reserve.total_collateral
+            },
+        };
+        emit!(ObligationLiquidated{
+            obligation : obligation.key(),
+            owner : obligation.owner,
+            liquidator : *ctx.accounts.liquidator.key,
+            repay_reserve : repay_reserve_key,
+            withdraw_reserve : withdraw_reserve_key,
+            repay_amount,
+            withdraw_amount,
+            borrowed_amount,
+            deposited_amount,
+            repay_total_liquidity : repay_reserve.total_liquidity()?,
+            repay_available_liquidity : repay_reserve.available_liquidity,
+            withdraw_total_collateral,
+        });
         Ok(())
     }
 
-    pub fn redeem_reserve_collateral(
-        ctx : Context<RedeemReserveCollateral>,
+    /// Withdraw collateral tokens held by the reserve that do not back any obligation
+    pub fn withdraw_excess_collateral(
+        ctx : Context<WithdrawExcessCollateral>,
         amount : u64,
         ) -> ProgramResult {
         let reserve_account_info = ctx.accounts.reserve.to_account_info().clone();
@@ -376,7 +799,11 @@ pub mod solana_anchor {
         if dest_collateral.mint != reserve.collateral_mint {
             return Err(LendingError::NotMatchLiquidityMint.into());
         }
-        if source_collateral.amount < amount {
+        if dest_collateral.owner != ctx.accounts.lending_market.fee_receiver {
+            return Err(LendingError::NotMatchFeeReceiver.into());
+        }
+        let excess = source_collateral.amount.saturating_sub(reserve.total_collateral);
+        if amount == 0 || amount > excess {
             return Err(LendingError::NotEnoughCollateral.into());
         }
 
@@ -393,9 +820,15 @@ pub mod solana_anchor {
                 authority : reserve_account_info,
                 authority_signer_seeds : lending_seeds,
                 token_program : ctx.accounts.token_program.clone(),
-                amount : amount,
+                amount,
             }
         )?;
+        emit!(ExcessCollateralWithdrawn{
+            reserve : reserve.key(),
+            destination : *ctx.accounts.dest_collateral.key,
+            amount,
+            remaining_excess : excess - amount,
+        });
         Ok(())
     }
 
@@ -403,15 +836,32 @@ pub mod solana_anchor {
         ctx : Context<DepositReserveLiquidity>,
         _amount : u64,
         ) -> ProgramResult {
+        let reserve_account_info = ctx.accounts.reserve.to_account_info().clone();
         let reserve = &mut ctx.accounts.reserve;
+        if !reserve.status.allows_deposit() {
+            return Err(LendingError::ReservePaused.into());
+        }
         if reserve.liquidity_account != *ctx.accounts.dest_liquidity.key {
             return Err(LendingError::NotMatchLiquidityAccount.into());
         }
+        if reserve.share_mint != *ctx.accounts.share_mint.key {
+            return Err(LendingError::NotMatchShareMint.into());
+        }
         let source_liquidity : state::Account = state::Account::unpack_from_slice(&ctx.accounts.source_liquidity.data.borrow())?;
         // let dest_liquidity   : state::Account = state::Account::unpack_from_slice(&ctx.accounts.dest_liquidity.data.borrow())?;
+        let dest_share : state::Account = state::Account::unpack_from_slice(&ctx.accounts.dest_share.data.borrow())?;
         if reserve.liquidity_mint != source_liquidity.mint {
             return Err(LendingError::NotMatchLiquidityMint.into());
         }
+        if reserve.share_mint != dest_share.mint {
+            return Err(LendingError::NotMatchShareMint.into());
+        }
+
+        accrue_reserve_interest(reserve, Clock::get()?.slot)?;
+        let share_amount = reserve.liquidity_to_shares(_amount)?;
+        if share_amount == 0 {
+            return Err(LendingError::InvalidAmount.into());
+        }
 
         spl_token_transfer_without_seed(
             TokenTransferParamsWithoutSeed{
@@ -422,80 +872,654 @@ pub mod solana_anchor {
                 amount : _amount,
             }
         )?;
+        reserve.available_liquidity = reserve.available_liquidity.try_add(_amount)?;
+
+        let lending_seeds = &[
+            reserve.lending_market.as_ref(),
+            reserve.collateral_mint.as_ref(),
+            reserve.liquidity_mint.as_ref(),
+            &[reserve.bump]
+        ];
 
+        spl_token_mint_to(
+            TokenMintToParams{
+                mint : ctx.accounts.share_mint.clone(),
+                destination : ctx.accounts.dest_share.clone(),
+                authority : reserve_account_info,
+                authority_signer_seeds : lending_seeds,
+                token_program : ctx.accounts.token_program.clone(),
+                amount : share_amount,
+            }
+        )?;
+        reserve.share_mint_total_supply = reserve.share_mint_total_supply.try_add(share_amount)?;
+        emit!(LiquidityDeposited{
+            reserve : reserve.key(),
+            owner : *ctx.accounts.owner.key,
+            liquidity_amount : _amount,
+            share_amount,
+            available_liquidity : reserve.available_liquidity,
+            share_mint_total_supply : reserve.share_mint_total_supply,
+        });
         Ok(())
     }
 
-    pub fn set_borrow_rate(
-        ctx : Context<SetBorrowRate>,
-        _borrow_rate_numerator : u64,
-        _borrow_rate_denominator : u64
+    pub fn redeem_reserve_liquidity(
+        ctx : Context<RedeemReserveLiquidity>,
+        share_amount : u64,
         ) -> ProgramResult {
+        let reserve_account_info = ctx.accounts.reserve.to_account_info().clone();
         let reserve = &mut ctx.accounts.reserve;
-        if reserve.lending_market != ctx.accounts.lending_market.key() {
-            return Err(LendingError::NotMatchLendingMarket.into());
+        if !reserve.status.allows_withdraw() {
+            return Err(LendingError::ReservePaused.into());
+        }
+        if reserve.liquidity_account != *ctx.accounts.source_liquidity.key {
+            return Err(LendingError::NotMatchLiquidityAccount.into());
+        }
+        if reserve.share_mint != *ctx.accounts.share_mint.key {
+            return Err(LendingError::NotMatchShareMint.into());
+        }
+        let source_share : state::Account = state::Account::unpack_from_slice(&ctx.accounts.source_share.data.borrow())?;
+        let dest_liquidity : state::Account = state::Account::unpack_from_slice(&ctx.accounts.dest_liquidity.data.borrow())?;
+        if reserve.share_mint != source_share.mint {
+            return Err(LendingError::NotMatchShareMint.into());
+        }
+        if reserve.liquidity_mint != dest_liquidity.mint {
+            return Err(LendingError::NotMatchLiquidityMint.into());
         }
-        reserve.max_borrow_rate_numerator=_borrow_rate_numerator;
-        reserve.max_borrow_rate_denominator=_borrow_rate_denominator;
+
+        accrue_reserve_interest(reserve, Clock::get()?.slot)?;
+        let liquidity_amount = reserve.shares_to_liquidity(share_amount)?;
+        if liquidity_amount == 0 {
+            return Err(LendingError::InvalidAmount.into());
+        }
+        if liquidity_amount > reserve.available_liquidity {
+            return Err(LendingError::NotEnoughLiquidity.into());
+        }
+
+        spl_token_burn(
+            TokenBurnParams{
+                mint : ctx.accounts.share_mint.clone(),
+                source : ctx.accounts.source_share.clone(),
+                authority : ctx.accounts.owner.clone(),
+                token_program : ctx.accounts.token_program.clone(),
+                amount : share_amount,
+            }
+        )?;
+
+        let lending_seeds = &[
+            reserve.lending_market.as_ref(),
+            reserve.collateral_mint.as_ref(),
+            reserve.liquidity_mint.as_ref(),
+            &[reserve.bump]
+        ];
+
+        spl_token_transfer(
+            TokenTransferParams{
+                source : ctx.accounts.source_liquidity.clone(),
+                destination : ctx.accounts.dest_liquidity.clone(),
+                authority : reserve_account_info,
+                authority_signer_seeds : lending_seeds,
+                token_program : ctx.accounts.token_program.clone(),
+                amount : liquidity_amount,
+            }
+        )?;
+        reserve.share_mint_total_supply = reserve.share_mint_total_supply.try_sub(share_amount)?;
+        reserve.available_liquidity = reserve.available_liquidity.try_sub(liquidity_amount)?;
+        emit!(LiquidityRedeemed{
+            reserve : reserve.key(),
+            owner : *ctx.accounts.owner.key,
+            liquidity_amount,
+            share_amount,
+            available_liquidity : reserve.available_liquidity,
+            share_mint_total_supply : reserve.share_mint_total_supply,
+        });
         Ok(())
     }
 
-    pub fn set_market_price(
-        ctx : Context<SetMarketPrice>,
-        _collateral_market_price : u128,
-        _collateral_market_price_decimals : u8,
+    pub fn flash_loan<'info>(
+        ctx : Context<'_, '_, '_, 'info, FlashLoan<'info>>,
+        liquidity_amount : u64,
         ) -> ProgramResult {
+        let reserve_account_info = ctx.accounts.reserve.to_account_info().clone();
         let reserve = &mut ctx.accounts.reserve;
-        if reserve.lending_market != ctx.accounts.lending_market.key() {
+        if !reserve.status.allows_borrow() {
+            return Err(LendingError::ReservePaused.into());
+        }
+        if reserve.lending_market != *ctx.accounts.lending_market.key {
             return Err(LendingError::NotMatchLendingMarket.into());
         }
-        if reserve.liquidity_oracle != *ctx.accounts.oracle_price.key {
-            return Err(LendingError::InvalidOracleConfig.into());
+        if reserve.liquidity_account != *ctx.accounts.source_liquidity.key {
+            return Err(LendingError::NotMatchLiquidityAccount.into());
         }
-
-        let aggregator : Aggregator = try_from_slice_unchecked(&ctx.accounts.oracle_price.data.borrow()[..4096])?;
-        let mut price : u128 = 0;
-        if let Some(answer) = aggregator.answer {
-            price = answer;
-        }else {
-            return Err(LendingError::InvalidOracleConfig.into());
+        if *ctx.accounts.flash_loan_receiver_program.key == *ctx.program_id {
+            return Err(LendingError::InvalidFlashLoanReceiver.into());
         }
+        let source_liquidity : state::Account = state::Account::unpack_from_slice(&ctx.accounts.source_liquidity.data.borrow())?;
+        let dest_liquidity : state::Account = state::Account::unpack_from_slice(&ctx.accounts.dest_liquidity.data.borrow())?;
+        if dest_liquidity.mint != reserve.liquidity_mint {
+            return Err(LendingError::NotMatchLiquidityMint.into());
+        }
+        if liquidity_amount == 0 || liquidity_amount > source_liquidity.amount {
+            return Err(LendingError::NotEnoughLiquidity.into());
+        }
+        let fee = Decimal::from(liquidity_amount).try_mul(Decimal::from_bps(reserve.flash_loan_fee_bps))?.try_ceil_u64()?;
+        let expected_balance = source_liquidity.amount.try_add(fee)?;
 
-        reserve.liquidity_market_price = price;
-        reserve.liquidity_market_price_decimals = aggregator.config.decimals;
+        let lending_seeds = &[
+            ctx.accounts.lending_market.key.as_ref(),
+            reserve.collateral_mint.as_ref(),
+            reserve.liquidity_mint.as_ref(),
+            &[reserve.bump]
+        ];
 
-        reserve.collateral_market_price = _collateral_market_price;
-        reserve.collateral_market_price_decimals = _collateral_market_price_decimals;
-        
-        // reserve.is_live = 1;
+        spl_token_transfer(
+            TokenTransferParams{
+                source : ctx.accounts.source_liquidity.clone(),
+                destination : ctx.accounts.dest_liquidity.clone(),
+                authority : reserve_account_info,
+                authority_signer_seeds : lending_seeds,
+                token_program : ctx.accounts.token_program.clone(),
+                amount : liquidity_amount,
+            }
+        )?;
+
+        let mut data = Vec::with_capacity(9);
+        data.push(FLASH_LOAN_RECEIVE_TAG);
+        data.extend_from_slice(&liquidity_amount.to_le_bytes());
+        let mut accounts = vec![
+            InstructionAccountMeta::new(*ctx.accounts.dest_liquidity.key, false),
+            InstructionAccountMeta::new(*ctx.accounts.source_liquidity.key, false),
+            InstructionAccountMeta::new_readonly(*ctx.accounts.token_program.key, false),
+        ];
+        let mut account_infos = vec![
+            ctx.accounts.dest_liquidity.clone(),
+            ctx.accounts.source_liquidity.clone(),
+            ctx.accounts.token_program.clone(),
+        ];
+        for account in ctx.remaining_accounts.iter() {
+            accounts.push(InstructionAccountMeta{
+                pubkey : *account.key,
+                is_signer : account.is_signer,
+                is_writable : account.is_writable,
+            });
+            account_infos.push(account.clone());
+        }
+        account_infos.push(ctx.accounts.flash_loan_receiver_program.clone());
+        invoke(
+            &Instruction{
+                program_id : *ctx.accounts.flash_loan_receiver_program.key,
+                accounts,
+                data,
+            },
+            &account_infos,
+        )?;
+
+        let source_liquidity : state::Account = state::Account::unpack_from_slice(&ctx.accounts.source_liquidity.data.borrow())?;
+        if source_liquidity.amount < expected_balance {
+            return Err(LendingError::FlashLoanNotRepaid.into());
+        }
+        reserve.available_liquidity = reserve.available_liquidity.try_add(fee)?;
+        emit!(FlashLoanRepaid{
+            reserve : reserve.key(),
+            receiver_program : *ctx.accounts.flash_loan_receiver_program.key,
+            amount : liquidity_amount,
+            fee,
+            available_liquidity : reserve.available_liquidity,
+        });
         Ok(())
     }
-}
 
-#[derive(Accounts)]
-pub struct SetMarketPrice<'info> {
-    #[account(mut,signer)]
-    owner : AccountInfo<'info>,
+    /// Queue new risk parameters for a reserve, they can be executed once the market config delay has passed
+    pub fn propose_reserve_config(
+        ctx : Context<ProposeReserveConfig>,
+        _bump : u8,
+        _config : ReserveConfig,
+        ) -> ProgramResult {
+        let reserve = &ctx.accounts.reserve;
+        if reserve.lending_market != ctx.accounts.lending_market.key() {
+            return Err(LendingError::NotMatchLendingMarket.into());
+        }
+        _config.validate()?;
+        let executable_at = Clock::get()?.unix_timestamp
+            .checked_add(ctx.accounts.lending_market.config_delay)
+            .ok_or(LendingError::MathOverflow)?;
+
+        let pending_config = &mut ctx.accounts.pending_config;
+        pending_config.reserve = reserve.key();
+        pending_config.config = _config;
+        pending_config.executable_at = executable_at;
+        pending_config.bump = _bump;
+        emit!(ReserveConfigProposed{
+            reserve : reserve.key(),
+            config : _config,
+            executable_at,
+        });
+        Ok(())
+    }
 
-    #[account(has_one=owner)]
-    lending_market : ProgramAccount<'info,LendingMarket>,
+    pub fn execute_reserve_config(
+        ctx : Context<ExecuteReserveConfig>,
+        ) -> ProgramResult {
+        let reserve = &mut ctx.accounts.reserve;
+        if reserve.lending_market != ctx.accounts.lending_market.key() {
+            return Err(LendingError::NotMatchLendingMarket.into());
+        }
+        let clock = Clock::get()?;
+        if clock.unix_timestamp < ctx.accounts.pending_config.executable_at {
+            return Err(LendingError::ConfigNotReady.into());
+        }
+        // settle interest and fees under the old parameters before switching
+        accrue_reserve_interest(reserve, clock.slot)?;
+        reserve.apply_config(&ctx.accounts.pending_config.config);
+        emit!(ReserveConfigExecuted{
+            reserve : reserve.key(),
+            config : ctx.accounts.pending_config.config,
+        });
+        Ok(())
+    }
+
+    pub fn cancel_reserve_config(
+        ctx : Context<CancelReserveConfig>,
+        ) -> ProgramResult {
+        if ctx.accounts.reserve.lending_market != ctx.accounts.lending_market.key() {
+            return Err(LendingError::NotMatchLendingMarket.into());
+        }
+        emit!(ReserveConfigCancelled{
+            reserve : ctx.accounts.reserve.key(),
+        });
+        Ok(())
+    }
+
+    /// The delay can only grow, otherwise the key it protects against could shorten it first
+    pub fn set_config_delay(
+        ctx : Context<SetConfigDelay>,
+        _config_delay : i64,
+        ) -> ProgramResult {
+        let lending_market = &mut ctx.accounts.lending_market;
+        if _config_delay < lending_market.config_delay {
+            return Err(LendingError::InvalidConfigDelay.into());
+        }
+        lending_market.config_delay = _config_delay;
+        emit!(ConfigDelayChanged{
+            lending_market : lending_market.key(),
+            config_delay : _config_delay,
+        });
+        Ok(())
+    }
+
+    pub fn set_fee_receiver(
+        ctx : Context<SetFeeReceiver>,
+        ) -> ProgramResult {
+        let lending_market = &mut ctx.accounts.lending_market;
+        lending_market.fee_receiver = *ctx.accounts.fee_receiver.key;
+        emit!(FeeReceiverChanged{
+            lending_market : lending_market.key(),
+            fee_receiver : lending_market.fee_receiver,
+        });
+        Ok(())
+    }
+
+    /// Send the protocol's share of interest to a token account of the market fee receiver
+    pub fn collect_protocol_fees(
+        ctx : Context<CollectProtocolFees>,
+        ) -> ProgramResult {
+        let reserve_account_info = ctx.accounts.reserve.to_account_info().clone();
+        let reserve = &mut ctx.accounts.reserve;
+        if reserve.lending_market != ctx.accounts.lending_market.key() {
+            return Err(LendingError::NotMatchLendingMarket.into());
+        }
+        if reserve.liquidity_account != *ctx.accounts.source_liquidity.key {
+            return Err(LendingError::NotMatchLiquidityAccount.into());
+        }
+        let dest_liquidity : state::Account = state::Account::unpack_from_slice(&ctx.accounts.dest_liquidity.data.borrow())?;
+        if dest_liquidity.mint != reserve.liquidity_mint {
+            return Err(LendingError::NotMatchLiquidityMint.into());
+        }
+        if dest_liquidity.owner != ctx.accounts.lending_market.fee_receiver {
+            return Err(LendingError::NotMatchFeeReceiver.into());
+        }
+
+        accrue_reserve_interest(reserve, Clock::get()?.slot)?;
+        // fees still lent out are collected once borrowers repay them
+        let amount = reserve.accumulated_protocol_fees.min(reserve.available_liquidity);
+        if amount == 0 {
+            return Err(LendingError::InvalidAmount.into());
+        }
+
+        let lending_seeds = &[
+            reserve.lending_market.as_ref(),
+            reserve.collateral_mint.as_ref(),
+            reserve.liquidity_mint.as_ref(),
+            &[reserve.bump]
+        ];
+
+        spl_token_transfer(
+            TokenTransferParams{
+                source : ctx.accounts.source_liquidity.clone(),
+                destination : ctx.accounts.dest_liquidity.clone(),
+                authority : reserve_account_info,
+                authority_signer_seeds : lending_seeds,
+                token_program : ctx.accounts.token_program.clone(),
+                amount,
+            }
+        )?;
+        reserve.accumulated_protocol_fees = reserve.accumulated_protocol_fees.try_sub(amount)?;
+        reserve.available_liquidity = reserve.available_liquidity.try_sub(amount)?;
+        emit!(ProtocolFeesCollected{
+            reserve : reserve.key(),
+            destination : *ctx.accounts.dest_liquidity.key,
+            amount,
+            accumulated_protocol_fees : reserve.accumulated_protocol_fees,
+            available_liquidity : reserve.available_liquidity,
+        });
+        Ok(())
+    }
+
+    pub fn set_price_override(
+        ctx : Context<SetPriceOverride>,
+        price_override : bool,
+        ) -> ProgramResult {
+        let reserve = &mut ctx.accounts.reserve;
+        if reserve.lending_market != ctx.accounts.lending_market.key() {
+            return Err(LendingError::NotMatchLendingMarket.into());
+        }
+        reserve.price_override = price_override;
+        emit!(PriceOverrideChanged{
+            reserve : reserve.key(),
+            price_override,
+        });
+        Ok(())
+    }
+
+    /// Queue new oracles for both sides, the new price accounts are passed in remaining accounts.
+    /// Oracles decide every price, so they wait for the market config delay like risk parameters
+    pub fn propose_reserve_oracles(
+        ctx : Context<ProposeReserveOracles>,
+        _bump : u8,
+        _liquidity_oracles : OracleSet,
+        _collateral_oracles : OracleSet,
+        ) -> ProgramResult {
+        let reserve = &ctx.accounts.reserve;
+        if reserve.lending_market != ctx.accounts.lending_market.key() {
+            return Err(LendingError::NotMatchLendingMarket.into());
+        }
+        check_oracle_accounts(&ctx.accounts.lending_market, &_liquidity_oracles, &_collateral_oracles, ctx.remaining_accounts)?;
+        let executable_at = Clock::get()?.unix_timestamp
+            .checked_add(ctx.accounts.lending_market.config_delay)
+            .ok_or(LendingError::MathOverflow)?;
+
+        let pending_oracles = &mut ctx.accounts.pending_oracles;
+        pending_oracles.reserve = reserve.key();
+        pending_oracles.liquidity_oracles = _liquidity_oracles;
+        pending_oracles.collateral_oracles = _collateral_oracles;
+        pending_oracles.executable_at = executable_at;
+        pending_oracles.bump = _bump;
+        emit!(ReserveOraclesProposed{
+            reserve : reserve.key(),
+            liquidity_oracles : pending_oracles.liquidity_oracles.clone(),
+            collateral_oracles : pending_oracles.collateral_oracles.clone(),
+            executable_at,
+        });
+        Ok(())
+    }
+
+    /// Switch to the queued oracles, whose price accounts are passed again in remaining accounts
+    /// in case the market oracle programs changed in the meantime
+    pub fn execute_reserve_oracles(
+        ctx : Context<ExecuteReserveOracles>,
+        ) -> ProgramResult {
+        let reserve = &mut ctx.accounts.reserve;
+        if reserve.lending_market != ctx.accounts.lending_market.key() {
+            return Err(LendingError::NotMatchLendingMarket.into());
+        }
+        let pending_oracles = &ctx.accounts.pending_oracles;
+        if Clock::get()?.unix_timestamp < pending_oracles.executable_at {
+            return Err(LendingError::ConfigNotReady.into());
+        }
+        check_oracle_accounts(&ctx.accounts.lending_market, &pending_oracles.liquidity_oracles, &pending_oracles.collateral_oracles, ctx.remaining_accounts)?;
+        reserve.liquidity_oracles = pending_oracles.liquidity_oracles.clone();
+        reserve.collateral_oracles = pending_oracles.collateral_oracles.clone();
+        emit!(ReserveOraclesChanged{
+            reserve : reserve.key(),
+            liquidity_oracles : reserve.liquidity_oracles.clone(),
+            collateral_oracles : reserve.collateral_oracles.clone(),
+        });
+        Ok(())
+    }
+
+    pub fn cancel_reserve_oracles(
+        ctx : Context<CancelReserveOracles>,
+        ) -> ProgramResult {
+        if ctx.accounts.reserve.lending_market != ctx.accounts.lending_market.key() {
+            return Err(LendingError::NotMatchLendingMarket.into());
+        }
+        emit!(ReserveOraclesCancelled{
+            reserve : ctx.accounts.reserve.key(),
+        });
+        Ok(())
+    }
+
+    /// Emergency path pinning both prices by hand near their TWAPs, only allowed while the override is on
+    pub fn set_market_price(
+        ctx : Context<SetMarketPrice>,
+        _liquidity_market_price : u128,
+        _liquidity_market_price_decimals : u8,
+        _collateral_market_price : u128,
+        _collateral_market_price_decimals : u8,
+        ) -> ProgramResult {
+        let reserve = &mut ctx.accounts.reserve;
+        if reserve.lending_market != ctx.accounts.lending_market.key() {
+            return Err(LendingError::NotMatchLendingMarket.into());
+        }
+        if !reserve.price_override {
+            return Err(LendingError::PriceOverrideDisabled.into());
+        }
+        // pinned prices stay near the average so the oracle admin alone cannot reprice positions
+        if !reserve.liquidity_price_twap.is_within(_liquidity_market_price, _liquidity_market_price_decimals, MAX_PRICE_OVERRIDE_DEVIATION_BPS)?
+            || !reserve.collateral_price_twap.is_within(_collateral_market_price, _collateral_market_price_decimals, MAX_PRICE_OVERRIDE_DEVIATION_BPS)? {
+            return Err(LendingError::PriceOverrideOutOfBounds.into());
+        }
+
+        reserve.liquidity_market_price = _liquidity_market_price;
+        reserve.liquidity_market_price_decimals = _liquidity_market_price_decimals;
+
+        reserve.collateral_market_price = _collateral_market_price;
+        reserve.collateral_market_price_decimals = _collateral_market_price_decimals;
+        let current_slot = Clock::get()?.slot;
+        reserve.market_price_slot = current_slot;
+        emit!(PriceUpdated{
+            reserve : reserve.key(),
+            liquidity_market_price : reserve.liquidity_market_price,
+            liquidity_market_price_decimals : reserve.liquidity_market_price_decimals,
+            collateral_market_price : reserve.collateral_market_price,
+            collateral_market_price_decimals : reserve.collateral_market_price_decimals,
+            slot : current_slot,
+        });
+        Ok(())
+    }
+}
+
+#[derive(Accounts)]
+#[instruction(_bump : u8)]
+pub struct ProposeReserveOracles<'info> {
+    #[account(init,
+        seeds=[reserve.key().as_ref(),b"pending_oracles".as_ref()],
+        bump=_bump,
+        payer=oracle_admin, space=8+PENDING_RESERVE_ORACLES_SIZE)]
+    pending_oracles : ProgramAccount<'info,PendingReserveOracles>,
+
+    #[account(mut)]
+    oracle_admin : Signer<'info>,
+
+    #[account(has_one=oracle_admin)]
+    lending_market : ProgramAccount<'info,LendingMarket>,
+
+    reserve : ProgramAccount<'info,Reserve>,
+
+    system_program : Program<'info,System>,
+}
+
+#[derive(Accounts)]
+pub struct ExecuteReserveOracles<'info> {
+    #[account(mut, has_one=reserve, close=oracle_admin)]
+    pending_oracles : ProgramAccount<'info,PendingReserveOracles>,
+
+    #[account(mut, signer)]
+    oracle_admin : AccountInfo<'info>,
+
+    #[account(has_one=oracle_admin)]
+    lending_market : ProgramAccount<'info,LendingMarket>,
+
+    #[account(mut)]
+    reserve : ProgramAccount<'info,Reserve>,
+}
+
+#[derive(Accounts)]
+pub struct CancelReserveOracles<'info> {
+    #[account(mut, has_one=reserve, close=oracle_admin)]
+    pending_oracles : ProgramAccount<'info,PendingReserveOracles>,
+
+    #[account(mut, signer)]
+    oracle_admin : AccountInfo<'info>,
+
+    #[account(has_one=oracle_admin)]
+    lending_market : ProgramAccount<'info,LendingMarket>,
 
-    #[account(mut)]
     reserve : ProgramAccount<'info,Reserve>,
+}
+
+#[derive(Accounts)]
+pub struct SetMarketPrice<'info> {
+    #[account(mut,signer)]
+    oracle_admin : AccountInfo<'info>,
 
-    oracle_price : AccountInfo<'info>,
+    #[account(has_one=oracle_admin)]
+    lending_market : ProgramAccount<'info,LendingMarket>,
+
+    #[account(mut)]
+    reserve : ProgramAccount<'info,Reserve>,
 }
 
 #[derive(Accounts)]
-pub struct SetBorrowRate<'info> {
+pub struct SetPriceOverride<'info> {
     #[account(mut,signer)]
+    oracle_admin : AccountInfo<'info>,
+
+    #[account(has_one=oracle_admin)]
+    lending_market : ProgramAccount<'info,LendingMarket>,
+
+    #[account(mut)]
+    reserve : ProgramAccount<'info,Reserve>,
+}
+
+#[derive(Accounts)]
+#[instruction(_bump : u8)]
+pub struct ProposeReserveConfig<'info> {
+    #[account(init,
+        seeds=[reserve.key().as_ref(),b"pending_config".as_ref()],
+        bump=_bump,
+        payer=risk_admin, space=8+PENDING_RESERVE_CONFIG_SIZE)]
+    pending_config : ProgramAccount<'info,PendingReserveConfig>,
+
+    #[account(mut)]
+    risk_admin : Signer<'info>,
+
+    #[account(has_one=risk_admin)]
+    lending_market : ProgramAccount<'info,LendingMarket>,
+
+    reserve : ProgramAccount<'info,Reserve>,
+
+    system_program : Program<'info,System>,
+}
+
+#[derive(Accounts)]
+pub struct ExecuteReserveConfig<'info> {
+    #[account(mut, has_one=reserve, close=risk_admin)]
+    pending_config : ProgramAccount<'info,PendingReserveConfig>,
+
+    #[account(mut, signer)]
+    risk_admin : AccountInfo<'info>,
+
+    #[account(has_one=risk_admin)]
+    lending_market : ProgramAccount<'info,LendingMarket>,
+
+    #[account(mut)]
+    reserve : ProgramAccount<'info,Reserve>,
+}
+
+#[derive(Accounts)]
+pub struct CancelReserveConfig<'info> {
+    #[account(mut, has_one=reserve, close=risk_admin)]
+    pending_config : ProgramAccount<'info,PendingReserveConfig>,
+
+    #[account(mut, signer)]
+    risk_admin : AccountInfo<'info>,
+
+    #[account(has_one=risk_admin)]
+    lending_market : ProgramAccount<'info,LendingMarket>,
+
+    reserve : ProgramAccount<'info,Reserve>,
+}
+
+#[derive(Accounts)]
+pub struct SetConfigDelay<'info> {
+    #[account(mut, has_one=owner)]
+    lending_market : ProgramAccount<'info,LendingMarket>,
+
+    #[account(signer)]
+    owner : AccountInfo<'info>,
+}
+
+#[derive(Accounts)]
+pub struct SetFeeReceiver<'info> {
+    #[account(mut, has_one=owner)]
+    lending_market : ProgramAccount<'info,LendingMarket>,
+
+    #[account(signer)]
     owner : AccountInfo<'info>,
 
-    #[account(has_one=owner)]
+    fee_receiver : AccountInfo<'info>,
+}
+
+#[derive(Accounts)]
+pub struct CollectProtocolFees<'info> {
+    #[account(signer)]
+    treasury : AccountInfo<'info>,
+
+    #[account(has_one=treasury)]
     lending_market : ProgramAccount<'info,LendingMarket>,
 
     #[account(mut)]
     reserve : ProgramAccount<'info,Reserve>,
+
+    #[account(mut)]
+    source_liquidity : AccountInfo<'info>,
+
+    #[account(mut,owner=spl_token::id())]
+    dest_liquidity : AccountInfo<'info>,
+
+    #[account(address=spl_token::id())]
+    token_program : AccountInfo<'info>,
+}
+
+#[derive(Accounts)]
+pub struct FlashLoan<'info> {
+    #[account(mut,owner=spl_token::id())]
+    source_liquidity : AccountInfo<'info>,
+
+    #[account(mut,owner=spl_token::id())]
+    dest_liquidity : AccountInfo<'info>,
+
+    #[account(mut)]
+    reserve : ProgramAccount<'info,Reserve>,
+
+    lending_market : AccountInfo<'info>,
+
+    #[account(executable)]
+    flash_loan_receiver_program : AccountInfo<'info>,
+
+    #[account(address=spl_token::id())]
+    token_program : AccountInfo<'info>,
 }
 
 #[derive(Accounts)]
@@ -509,6 +1533,12 @@ pub struct DepositReserveLiquidity<'info> {
     #[account(mut,owner=spl_token::id())]
     dest_liquidity : AccountInfo<'info>,
 
+    #[account(mut,owner=spl_token::id())]
+    share_mint : AccountInfo<'info>,
+
+    #[account(mut,owner=spl_token::id())]
+    dest_share : AccountInfo<'info>,
+
     #[account(mut)]
     reserve : ProgramAccount<'info,Reserve>,
 
@@ -517,10 +1547,34 @@ pub struct DepositReserveLiquidity<'info> {
 }
 
 #[derive(Accounts)]
-pub struct RedeemReserveCollateral<'info> {
+pub struct RedeemReserveLiquidity<'info> {
     #[account(mut,signer)]
     owner : AccountInfo<'info>,
 
+    #[account(mut,owner=spl_token::id())]
+    source_share : AccountInfo<'info>,
+
+    #[account(mut,owner=spl_token::id())]
+    share_mint : AccountInfo<'info>,
+
+    #[account(mut,owner=spl_token::id())]
+    source_liquidity : AccountInfo<'info>,
+
+    #[account(mut,owner=spl_token::id())]
+    dest_liquidity : AccountInfo<'info>,
+
+    #[account(mut)]
+    reserve : ProgramAccount<'info,Reserve>,
+
+    #[account(address=spl_token::id())]
+    token_program : AccountInfo<'info>,
+}
+
+#[derive(Accounts)]
+pub struct WithdrawExcessCollateral<'info> {
+    #[account(mut,signer)]
+    treasury : AccountInfo<'info>,
+
     #[account(mut,owner=spl_token::id())]
     source_collateral : AccountInfo<'info>,
 
@@ -530,7 +1584,7 @@ pub struct RedeemReserveCollateral<'info> {
     #[account(mut)]
     reserve : ProgramAccount<'info,Reserve>,
 
-    #[account(has_one=owner)]
+    #[account(has_one=treasury)]
     lending_market : ProgramAccount<'info,LendingMarket>,
 
     #[account(address=spl_token::id())]
@@ -551,7 +1605,7 @@ pub struct RepayLiquidity<'info> {
     #[account(mut)]
     reserve : ProgramAccount<'info,Reserve>,
 
-    #[account(mut,seeds=[reserve.key().as_ref(),(*owner.key).as_ref()], bump=obligation.bump,has_one=owner)]
+    #[account(mut,seeds=[obligation.lending_market.as_ref(),(*owner.key).as_ref()], bump=obligation.bump,has_one=owner)]
     obligation : ProgramAccount<'info,Obligation>,
 
     #[account(address=spl_token::id())]
@@ -559,206 +1613,977 @@ pub struct RepayLiquidity<'info> {
 }
 
 #[derive(Accounts)]
-pub struct BorrowLiquidity<'info> {
+pub struct LiquidateObligation<'info> {
     #[account(mut,signer)]
-    owner : AccountInfo<'info>,
+    liquidator : AccountInfo<'info>,
 
     #[account(mut,owner=spl_token::id())]
-    collateral_mint : AccountInfo<'info>,
+    source_liquidity : AccountInfo<'info>,
 
     #[account(mut,owner=spl_token::id())]
-    liquidity_mint : AccountInfo<'info>,
+    dest_liquidity : AccountInfo<'info>,
 
     #[account(mut,owner=spl_token::id())]
-    source_liquidity : AccountInfo<'info>,
+    source_collateral : AccountInfo<'info>,
 
     #[account(mut,owner=spl_token::id())]
-    dest_liquidity : AccountInfo<'info>,
+    dest_collateral : AccountInfo<'info>,
 
     #[account(mut)]
-    reserve : ProgramAccount<'info,Reserve>,
+    repay_reserve : ProgramAccount<'info,Reserve>,
 
-    #[account(mut,seeds=[reserve.key().as_ref(),(*owner.key).as_ref()], bump=obligation.bump,)]
-    obligation : ProgramAccount<'info,Obligation>,
+    #[account(mut)]
+    withdraw_reserve : AccountInfo<'info>,
+
+    #[account(mut)]
+    obligation : ProgramAccount<'info,Obligation>,
 
     lending_market : AccountInfo<'info>,
 
-    oracle_price : AccountInfo<'info>,
+    #[account(address=spl_token::id())]
+    token_program : AccountInfo<'info>,
+}
+
+#[derive(Accounts)]
+pub struct BorrowLiquidity<'info> {
+    #[account(mut,signer)]
+    owner : AccountInfo<'info>,
+
+    #[account(mut,owner=spl_token::id())]
+    source_liquidity : AccountInfo<'info>,
+
+    #[account(mut,owner=spl_token::id())]
+    dest_liquidity : AccountInfo<'info>,
+
+    #[account(mut)]
+    reserve : ProgramAccount<'info,Reserve>,
+
+    #[account(mut,seeds=[lending_market.key.as_ref(),(*owner.key).as_ref()], bump=obligation.bump,has_one=owner)]
+    obligation : ProgramAccount<'info,Obligation>,
+
+    lending_market : AccountInfo<'info>,
 
     #[account(address=spl_token::id())]
     token_program : AccountInfo<'info>,
 }
 
-#[derive(Accounts)]
-pub struct WithdrawCollateral<'info> {
-    #[account(mut,signer)]
-    owner : AccountInfo<'info>,
+#[derive(Accounts)]
+pub struct WithdrawCollateral<'info> {
+    #[account(mut,signer)]
+    owner : AccountInfo<'info>,
+
+    #[account(mut,owner=spl_token::id())]
+    source_collateral : AccountInfo<'info>,
+
+    #[account(mut,owner=spl_token::id())]
+    dest_collateral : AccountInfo<'info>,
+
+    #[account(mut)]
+    reserve : ProgramAccount<'info,Reserve>,
+
+    #[account(mut,seeds=[lending_market.key.as_ref(),(*owner.key).as_ref()], bump=obligation.bump,has_one=owner)]
+    obligation : ProgramAccount<'info,Obligation>,
+
+    lending_market : AccountInfo<'info>,
+
+    #[account(address=spl_token::id())]
+    token_program : AccountInfo<'info>,
+}
+
+#[derive(Accounts)]
+pub struct DepositCollateral<'info> {
+    #[account(mut,signer)]
+    owner : AccountInfo<'info>,
+
+    #[account(mut,owner=spl_token::id())]
+    source_collateral : AccountInfo<'info>,
+
+    #[account(mut,owner=spl_token::id())]
+    dest_collateral : AccountInfo<'info>,
+
+    #[account(mut)]
+    reserve : ProgramAccount<'info,Reserve>,
+
+    #[account(mut,seeds=[obligation.lending_market.as_ref(),(*owner.key).as_ref()], bump=obligation.bump, has_one=owner)]
+    obligation : ProgramAccount<'info,Obligation>,
+
+    #[account(address=spl_token::id())]
+    token_program : AccountInfo<'info>,
+}
+
+#[derive(Accounts)]
+#[instruction(_bump : u8)]
+pub struct InitObligation<'info> {
+    #[account(init, seeds=[lending_market.key().as_ref(),(*owner.key).as_ref()], bump=_bump, payer=owner, space=8+OBLIGATION_SIZE)]
+    obligation : ProgramAccount<'info,Obligation>,
+
+    #[account(mut,signer)]
+    owner : AccountInfo<'info>,
+
+    lending_market : ProgramAccount<'info,LendingMarket>,
+
+    system_program : Program<'info,System>,
+}
+
+// seeds=[lending_market.key().as_ref(),(*liquidity_mint.key).as_ref(),(*collateral_mint.key).as_ref()], bump=bump ,
+// #[instruction(bump : u8,_max_borrow_rate : u8)]
+#[derive(Accounts)]
+#[instruction(_bump : u8)]
+pub struct InitReserve<'info> {
+    #[account(init, 
+        seeds=[lending_market.key().as_ref(),(*collateral_mint.key).as_ref(),(*liquidity_mint.key).as_ref()],
+        bump=_bump,
+        payer=owner, space=8+RESERVE_SIZE)]
+    reserve : ProgramAccount<'info,Reserve>,
+
+    #[account(mut)]
+    owner : Signer<'info>,
+
+    #[account(mut,has_one=owner)]
+    lending_market : ProgramAccount<'info,LendingMarket>,
+
+    #[account(owner=spl_token::id())]
+    liquidity_mint : AccountInfo<'info>,
+
+    #[account(owner=spl_token::id())]
+    liquidity_account : AccountInfo<'info>,
+
+    #[account(owner=spl_token::id())]
+    collateral_mint : AccountInfo<'info>,
+
+    #[account(owner=spl_token::id())]
+    collateral_account : AccountInfo<'info>,
+
+    #[account(owner=spl_token::id())]
+    share_mint : AccountInfo<'info>,
+
+    system_program : Program<'info,System>,
+}
+
+#[derive(Accounts)]
+pub struct SetLendingMarketOwner<'info> {
+    #[account(mut, has_one=owner)]
+    lending_market : ProgramAccount<'info,LendingMarket>,
+
+    #[account(mut, signer)]
+    owner : AccountInfo<'info>,
+
+    new_owner : AccountInfo<'info>,
+}
+
+#[derive(Accounts)]
+pub struct AcceptLendingMarketOwner<'info> {
+    #[account(mut)]
+    lending_market : ProgramAccount<'info,LendingMarket>,
+
+    #[account(signer)]
+    new_owner : AccountInfo<'info>,
+}
+
+#[derive(Accounts)]
+pub struct SetLendingMarketRoles<'info> {
+    #[account(mut, has_one=owner)]
+    lending_market : ProgramAccount<'info,LendingMarket>,
+
+    #[account(signer)]
+    owner : AccountInfo<'info>,
+
+    risk_admin : AccountInfo<'info>,
+
+    oracle_admin : AccountInfo<'info>,
+
+    pause_guardian : AccountInfo<'info>,
+
+    treasury : AccountInfo<'info>,
+}
+
+#[derive(Accounts)]
+pub struct CancelLendingMarketOwner<'info> {
+    #[account(mut, has_one=owner)]
+    lending_market : ProgramAccount<'info,LendingMarket>,
+
+    #[account(signer)]
+    owner : AccountInfo<'info>,
+}
+
+#[derive(Accounts)]
+pub struct RefreshReserve<'info> {
+    #[account(mut)]
+    reserve : ProgramAccount<'info,Reserve>,
+}
+
+#[derive(Accounts)]
+pub struct ReserveLiveControl<'info> {
+    #[account(mut)]
+    reserve : ProgramAccount<'info,Reserve>,
+
+    /// market owner, or the pause guardian when only pausing
+    #[account(signer)]
+    authority : AccountInfo<'info>,
+
+    lending_market : ProgramAccount<'info,LendingMarket>,
+}
+
+#[derive(Accounts)]
+pub struct InitLendingMarket<'info> {
+    #[account(init, payer=authority, space=8+LENDING_MARKET_SIZE)]
+    lending_market : ProgramAccount<'info,LendingMarket>,
+
+    #[account(mut)]
+    authority : Signer<'info>,
+
+    oracle_program_id : AccountInfo<'info>,
+
+    pyth_program_id : AccountInfo<'info>,
+
+    switchboard_program_id : AccountInfo<'info>,
+
+    system_program : Program<'info,System>
+}
+
+#[account]
+pub struct LendingMarket{
+    pub owner : Pubkey,
+    /// owner proposed by set_lending_market_owner, default while no transfer is pending
+    pub pending_owner : Pubkey,
+    /// sets borrow rates, loan-to-values, liquidation parameters, fees and caps
+    pub risk_admin : Pubkey,
+    /// configures reserve oracles and the manual price override
+    pub oracle_admin : Pubkey,
+    /// can pause reserves but never lift a pause
+    pub pause_guardian : Pubkey,
+    /// moves protocol fees and reserve collateral out of the market
+    pub treasury : Pubkey,
+    pub oracle_program_id : Pubkey,
+    pub pyth_program_id : Pubkey,
+    pub switchboard_program_id : Pubkey,
+    /// owner of the token accounts protocol fees are collected to
+    pub fee_receiver : Pubkey,
+    /// seconds between proposing reserve risk parameters or oracles and being able to execute them
+    pub config_delay : i64,
+}
+
+impl LendingMarket {
+    /// Program expected to own price accounts of the given source
+    pub fn oracle_program(&self, source : OracleSource) -> Pubkey {
+        match source {
+            OracleSource::Chainlink => self.oracle_program_id,
+            OracleSource::Pyth => self.pyth_program_id,
+            OracleSource::Switchboard => self.switchboard_program_id,
+        }
+    }
+}
+
+/// Risk parameters of a reserve, changed only through the timelocked proposal flow
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, Default, Debug, PartialEq, Eq)]
+pub struct ReserveConfig {
+    pub max_borrow_rate_numerator : u64,
+    pub max_borrow_rate_denominator : u64,
+    pub liquidation_threshold_numerator : u64,
+    pub liquidation_threshold_denominator : u64,
+    pub interest_rate_config : InterestRateConfig,
+    pub liquidation_bonus : u8,
+    pub liquidation_close_factor : u8,
+    pub flash_loan_fee_bps : u16,
+    pub protocol_fee_rate : u8,
+    pub borrow_fee_bps : u16,
+    pub host_fee_percentage : u8,
+    pub deposit_limit : u64,
+    pub borrow_limit : u64,
+}
+
+impl ReserveConfig {
+    pub fn validate(&self) -> ProgramResult {
+        if !is_valid_borrow_rate(
+            self.max_borrow_rate_numerator,
+            self.max_borrow_rate_denominator,
+            self.liquidation_threshold_numerator,
+            self.liquidation_threshold_denominator,
+        ) {
+            return Err(LendingError::InvalidBorrowRate.into());
+        }
+        if !self.interest_rate_config.is_valid() {
+            return Err(LendingError::InvalidReserveConfig.into());
+        }
+        if !is_valid_liquidation_config(self.liquidation_bonus, self.liquidation_close_factor) {
+            return Err(LendingError::InvalidReserveConfig.into());
+        }
+        if self.flash_loan_fee_bps as u64 > BPS_DENOMINATOR {
+            return Err(LendingError::InvalidReserveConfig.into());
+        }
+        if self.protocol_fee_rate > 100 {
+            return Err(LendingError::InvalidReserveConfig.into());
+        }
+        if !is_valid_borrow_fee(self.borrow_fee_bps, self.host_fee_percentage) {
+            return Err(LendingError::InvalidReserveConfig.into());
+        }
+        Ok(())
+    }
+}
+
+/// Reserve risk parameters waiting for the market config delay to pass
+#[account]
+pub struct PendingReserveConfig {
+    pub reserve : Pubkey,
+    pub config : ReserveConfig,
+    pub executable_at : UnixTimestamp,
+    pub bump : u8,
+}
+
+/// Reserve oracles waiting for the market config delay to pass
+#[account]
+pub struct PendingReserveOracles {
+    pub reserve : Pubkey,
+    pub liquidity_oracles : OracleSet,
+    pub collateral_oracles : OracleSet,
+    pub executable_at : UnixTimestamp,
+    pub bump : u8,
+}
+
+#[account]
+pub struct Reserve{
+    pub status : ReserveStatus,
+    pub lending_market : Pubkey,
+    pub liquidity_mint : Pubkey,
+    pub liquidity_mint_decimals : u8,
+    pub liquidity_account : Pubkey,
+    pub liquidity_oracles : OracleSet,
+    pub collateral_oracles : OracleSet,
+    pub price_override : bool,
+    pub collateral_mint : Pubkey,
+    pub collateral_mint_decimals : u8,
+    pub collateral_account : Pubkey,
+    pub share_mint : Pubkey,
+    pub share_mint_total_supply : u64,
+    /// liquidity lent out plus accrued interest, scaled by WAD
+    pub borrowed_amount_wads : u128,
+    pub total_collateral : u64,
+    pub max_borrow_rate_numerator : u64,
+    pub max_borrow_rate_denominator : u64,
+    pub liquidation_threshold_numerator : u64,
+    pub liquidation_threshold_denominator : u64,
+    pub liquidity_market_price : u128,
+    pub liquidity_market_price_decimals : u8,
+    pub collateral_market_price : u128,
+    pub collateral_market_price_decimals : u8,
+    pub market_price_slot : u64,
+    pub liquidity_price_twap : PriceTwap,
+    pub collateral_price_twap : PriceTwap,
+    pub last_update_slot : u64,
+    pub cumulative_borrow_rate_wads : u128,
+    pub available_liquidity : u64,
+    pub interest_rate_config : InterestRateConfig,
+    pub liquidation_bonus : u8,
+    pub liquidation_close_factor : u8,
+    pub flash_loan_fee_bps : u16,
+    /// share of accrued interest kept by the protocol, in percent
+    pub protocol_fee_rate : u8,
+    /// protocol fees owed by the reserve, counted in the borrowed amount until collected
+    pub accumulated_protocol_fees : u64,
+    /// origination fee added to every borrow, in basis points
+    pub borrow_fee_bps : u16,
+    /// share of the origination fee paid to the host fee account, in percent
+    pub host_fee_percentage : u8,
+    /// cap on total_collateral
+    pub deposit_limit : u64,
+    /// cap on the borrowed amount, interest accrual may still push it above
+    pub borrow_limit : u64,
+    pub bump : u8,
+}
+
+impl Reserve {
+    pub fn apply_config(&mut self, config : &ReserveConfig) {
+        self.max_borrow_rate_numerator = config.max_borrow_rate_numerator;
+        self.max_borrow_rate_denominator = config.max_borrow_rate_denominator;
+        self.liquidation_threshold_numerator = config.liquidation_threshold_numerator;
+        self.liquidation_threshold_denominator = config.liquidation_threshold_denominator;
+        self.interest_rate_config = config.interest_rate_config;
+        self.liquidation_bonus = config.liquidation_bonus;
+        self.liquidation_close_factor = config.liquidation_close_factor;
+        self.flash_loan_fee_bps = config.flash_loan_fee_bps;
+        self.protocol_fee_rate = config.protocol_fee_rate;
+        self.borrow_fee_bps = config.borrow_fee_bps;
+        self.host_fee_percentage = config.host_fee_percentage;
+        self.deposit_limit = config.deposit_limit;
+        self.borrow_limit = config.borrow_limit;
+    }
+
+    /// Compound interest on the outstanding borrows up to `current_slot`
+    pub fn accrue_interest(&mut self, current_slot : u64) -> ProgramResult {
+        let slots_elapsed = current_slot.try_sub(self.last_update_slot)?;
+        if slots_elapsed == 0 {
+            return Ok(());
+        }
+        let borrow_rate = current_borrow_rate(&self.interest_rate_config, self.total_liquidity()?, self.available_liquidity)?;
+        let slot_rate = borrow_rate.try_div(SLOTS_PER_YEAR)?;
+        let compounded_rate = Rate::one().try_add(slot_rate)?.try_pow(slots_elapsed)?;
+
+        self.cumulative_borrow_rate_wads = Rate::from_scaled_val(self.cumulative_borrow_rate_wads).try_mul(compounded_rate)?.to_scaled_val();
+        let borrowed_amount = self.borrowed_amount()?.try_mul(compounded_rate)?;
+        let protocol_fee = borrowed_amount.try_sub(self.borrowed_amount()?)?
+            .try_mul(Decimal::from_percent(self.protocol_fee_rate))?
+            .try_floor_u64()?;
+        self.accumulated_protocol_fees = self.accumulated_protocol_fees.try_add(protocol_fee)?;
+        self.borrowed_amount_wads = borrowed_amount.to_scaled_val();
+        self.last_update_slot = current_slot;
+        Ok(())
+    }
+
+    /// Record the current market prices in their time weighted averages
+    pub fn update_price_twaps(&mut self, current_slot : u64) -> ProgramResult {
+        self.liquidity_price_twap.update(self.liquidity_market_price, self.liquidity_market_price_decimals, current_slot)?;
+        self.collateral_price_twap.update(self.collateral_market_price, self.collateral_market_price_decimals, current_slot)?;
+        Ok(())
+    }
+
+    /// Collateral price under `basis`, in the decimals of the spot price
+    pub fn collateral_price(&self, basis : PriceBasis) -> std::result::Result<u128, ProgramError> {
+        match basis {
+            PriceBasis::Conservative => self.collateral_price_twap.min_price(self.collateral_market_price, self.collateral_market_price_decimals),
+            PriceBasis::Liquidation => self.collateral_price_twap.max_price(self.collateral_market_price, self.collateral_market_price_decimals),
+        }
+    }
+
+    /// Liquidity price under `basis`, in the decimals of the spot price
+    pub fn liquidity_price(&self, basis : PriceBasis) -> std::result::Result<u128, ProgramError> {
+        match basis {
+            PriceBasis::Conservative => self.liquidity_price_twap.max_price(self.liquidity_market_price, self.liquidity_market_price_decimals),
+            PriceBasis::Liquidation => self.liquidity_price_twap.min_price(self.liquidity_market_price, self.liquidity_market_price_decimals),
+        }
+    }
+
+    /// Value of `amount` collateral tokens at the collateral price under `basis`
+    pub fn collateral_value(&self, amount : u64, basis : PriceBasis) -> std::result::Result<Decimal, ProgramError> {
+        market_value(amount, self.collateral_price(basis)?, self.collateral_mint_decimals as u32 + self.collateral_market_price_decimals as u32)
+    }
+
+    /// Collateral tokens worth `value` at the price used by `collateral_value`
+    pub fn collateral_amount(&self, value : Decimal, basis : PriceBasis) -> std::result::Result<u64, ProgramError> {
+        market_amount(value, self.collateral_price(basis)?, self.collateral_mint_decimals as u32 + self.collateral_market_price_decimals as u32)
+    }
+
+    /// Value of `amount` liquidity tokens at the liquidity price under `basis`
+    pub fn liquidity_value(&self, amount : u64, basis : PriceBasis) -> std::result::Result<Decimal, ProgramError> {
+        market_value(amount, self.liquidity_price(basis)?, self.liquidity_mint_decimals as u32 + self.liquidity_market_price_decimals as u32)
+    }
+
+    /// (origination fee, host share of it) charged on a borrow of `liquidity_amount`
+    pub fn borrow_fees(&self, liquidity_amount : u64) -> std::result::Result<(u64, u64), ProgramError> {
+        let borrow_fee = Decimal::from(liquidity_amount).try_mul(Decimal::from_bps(self.borrow_fee_bps))?.try_ceil_u64()?;
+        let host_fee = Decimal::from(borrow_fee).try_mul(Decimal::from_percent(self.host_fee_percentage))?.try_floor_u64()?;
+        Ok((borrow_fee, host_fee))
+    }
+
+    pub fn borrowed_amount(&self) -> std::result::Result<Decimal, ProgramError> {
+        Ok(Decimal::from_scaled_val(self.borrowed_amount_wads))
+    }
+
+    /// Borrowed amount in whole tokens, dropping the rounding dust settlements can leave behind
+    pub fn total_liquidity(&self) -> std::result::Result<u64, ProgramError> {
+        self.borrowed_amount()?.try_floor_u64()
+    }
+
+    /// Remove debt settled by an obligation from the borrowed amount.
+    /// Obligations and the reserve compound separately, so the last repayment may exceed the reserve total by rounding dust
+    pub fn settle(&mut self, settled_amount : Decimal) -> ProgramResult {
+        let borrowed_amount = self.borrowed_amount()?;
+        self.borrowed_amount_wads = borrowed_amount.try_sub(settled_amount.min(borrowed_amount))?.to_scaled_val();
+        Ok(())
+    }
+
+    /// Liquidity owed to depositors, including the amount currently lent out and excluding protocol fees
+    pub fn total_supply(&self) -> std::result::Result<Decimal, ProgramError> {
+        Decimal::from(self.available_liquidity)
+            .try_add(self.borrowed_amount()?)?
+            .try_sub(Decimal::from(self.accumulated_protocol_fees))
+    }
+
+    /// Shares minted for a deposit of `liquidity_amount` at the current exchange rate
+    pub fn liquidity_to_shares(&self, liquidity_amount : u64) -> std::result::Result<u64, ProgramError> {
+        let total_supply = self.total_supply()?;
+        if self.share_mint_total_supply == 0 || total_supply == Decimal::zero() {
+            return Ok(liquidity_amount);
+        }
+        Decimal::from(liquidity_amount)
+            .try_mul_div(Decimal::from(self.share_mint_total_supply).to_scaled_val(), total_supply.to_scaled_val())?
+            .try_floor_u64()
+    }
+
+    /// Liquidity released when burning `share_amount` at the current exchange rate
+    pub fn shares_to_liquidity(&self, share_amount : u64) -> std::result::Result<u64, ProgramError> {
+        if self.share_mint_total_supply == 0 {
+            return Err(LendingError::InvalidAmount.into());
+        }
+        Decimal::from(share_amount)
+            .try_mul_div(self.total_supply()?.to_scaled_val(), Decimal::from(self.share_mint_total_supply).to_scaled_val())?
+            .try_floor_u64()
+    }
+}
+
+/// Operations a reserve currently accepts, repayments and liquidations are never paused
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ReserveStatus {
+    Active,
+    DepositsPaused,
+    BorrowsPaused,
+    Frozen,
+}
+
+impl ReserveStatus {
+    pub fn allows_deposit(&self) -> bool {
+        matches!(self, ReserveStatus::Active | ReserveStatus::BorrowsPaused)
+    }
+
+    pub fn allows_borrow(&self) -> bool {
+        matches!(self, ReserveStatus::Active | ReserveStatus::DepositsPaused)
+    }
+
+    pub fn allows_withdraw(&self) -> bool {
+        *self != ReserveStatus::Frozen
+    }
+
+    /// Whether every operation allowed by `self` is also allowed by `other`
+    pub fn is_at_least_as_restrictive_as(&self, other : &ReserveStatus) -> bool {
+        (!self.allows_deposit() || other.allows_deposit())
+            && (!self.allows_borrow() || other.allows_borrow())
+            && (!self.allows_withdraw() || other.allows_withdraw())
+    }
+}
+
+#[account]
+pub struct Obligation{
+    pub lending_market : Pubkey,
+    pub owner : Pubkey,
+    pub deposits : Vec<ObligationCollateral>,
+    pub borrows : Vec<ObligationLiquidity>,
+    pub bump : u8,
+}
+
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, Default, Debug)]
+pub struct ObligationCollateral {
+    pub reserve : Pubkey,
+    pub deposited_amount : u64,
+}
+
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, Default, Debug)]
+pub struct ObligationLiquidity {
+    pub reserve : Pubkey,
+    pub borrowed_amount_wads : u128,
+    pub cumulative_borrow_rate_wads : u128,
+}
+
+impl Obligation {
+    pub fn find_deposit_mut(&mut self, reserve : &Pubkey) -> std::result::Result<&mut ObligationCollateral, ProgramError> {
+        self.deposits.iter_mut().find(|deposit| deposit.reserve == *reserve)
+            .ok_or_else(|| LendingError::ObligationReserveNotFound.into())
+    }
 
-    #[account(mut,owner=spl_token::id())]
-    collateral_mint : AccountInfo<'info>,
+    pub fn find_borrow_mut(&mut self, reserve : &Pubkey) -> std::result::Result<&mut ObligationLiquidity, ProgramError> {
+        self.borrows.iter_mut().find(|borrow| borrow.reserve == *reserve)
+            .ok_or_else(|| LendingError::ObligationReserveNotFound.into())
+    }
 
-    #[account(mut,owner=spl_token::id())]
-    liquidity_mint : AccountInfo<'info>,
+    pub fn find_or_add_deposit(&mut self, reserve : Pubkey) -> std::result::Result<&mut ObligationCollateral, ProgramError> {
+        let index = match self.deposits.iter().position(|deposit| deposit.reserve == reserve) {
+            Some(index) => index,
+            None => {
+                if self.deposits.len() >= MAX_OBLIGATION_DEPOSITS {
+                    return Err(LendingError::ObligationReserveLimit.into());
+                }
+                self.deposits.push(ObligationCollateral{ reserve, deposited_amount : 0 });
+                self.deposits.len() - 1
+            }
+        };
+        Ok(&mut self.deposits[index])
+    }
 
-    #[account(mut,owner=spl_token::id())]
-    source_collateral : AccountInfo<'info>,
+    pub fn find_or_add_borrow(&mut self, reserve : Pubkey, cumulative_borrow_rate_wads : u128) -> std::result::Result<&mut ObligationLiquidity, ProgramError> {
+        let index = match self.borrows.iter().position(|borrow| borrow.reserve == reserve) {
+            Some(index) => index,
+            None => {
+                if self.borrows.len() >= MAX_OBLIGATION_BORROWS {
+                    return Err(LendingError::ObligationReserveLimit.into());
+                }
+                self.borrows.push(ObligationLiquidity{ reserve, borrowed_amount_wads : 0, cumulative_borrow_rate_wads });
+                self.borrows.len() - 1
+            }
+        };
+        Ok(&mut self.borrows[index])
+    }
 
-    #[account(mut,owner=spl_token::id())]
-    dest_collateral : AccountInfo<'info>,
+    /// Drop fully withdrawn deposits and fully repaid borrows so their slots can be reused
+    pub fn remove_empty_positions(&mut self) {
+        self.deposits.retain(|deposit| deposit.deposited_amount != 0);
+        self.borrows.retain(|borrow| borrow.borrowed_amount_wads != 0);
+    }
+}
 
-    #[account(mut)]
-    reserve : ProgramAccount<'info,Reserve>,
+impl ObligationLiquidity {
+    /// Borrowed amount grown by the reserve index change since the last snapshot
+    pub fn accrued_amount(&self, cumulative_borrow_rate_wads : u128) -> std::result::Result<Decimal, ProgramError> {
+        if self.borrowed_amount_wads == 0 || self.cumulative_borrow_rate_wads == 0 {
+            return self.borrowed_amount();
+        }
+        self.borrowed_amount()?.try_mul_div(cumulative_borrow_rate_wads, self.cumulative_borrow_rate_wads)
+    }
 
-    #[account(mut,seeds=[reserve.key().as_ref(),(*owner.key).as_ref()], bump=obligation.bump,has_one=owner)]
-    obligation : ProgramAccount<'info,Obligation>,
+    pub fn borrowed_amount(&self) -> std::result::Result<Decimal, ProgramError> {
+        Ok(Decimal::from_scaled_val(self.borrowed_amount_wads))
+    }
 
-    lending_market : AccountInfo<'info>,
+    /// Whole tokens needed to repay the debt in full
+    pub fn owed_amount(&self) -> std::result::Result<u64, ProgramError> {
+        self.borrowed_amount()?.try_ceil_u64()
+    }
 
-    oracle_price : AccountInfo<'info>,
+    /// Reduce the debt by a repayment of `amount`, a repayment of the full owed amount clears it.
+    /// Returns the debt removed, which the reserve must settle as well
+    pub fn settle(&mut self, amount : u64) -> std::result::Result<Decimal, ProgramError> {
+        let borrowed_amount = self.borrowed_amount()?;
+        let settled_amount = if amount >= self.owed_amount()? {
+            borrowed_amount
+        } else {
+            Decimal::from(amount)
+        };
+        self.borrowed_amount_wads = borrowed_amount.try_sub(settled_amount)?.to_scaled_val();
+        Ok(settled_amount)
+    }
 
-    #[account(address=spl_token::id())]
-    token_program : AccountInfo<'info>,
+    pub fn accrue_interest(&mut self, cumulative_borrow_rate_wads : u128) -> ProgramResult {
+        self.borrowed_amount_wads = self.accrued_amount(cumulative_borrow_rate_wads)?.to_scaled_val();
+        self.cumulative_borrow_rate_wads = cumulative_borrow_rate_wads;
+        Ok(())
+    }
 }
 
-#[derive(Accounts)]
-pub struct DepositCollateral<'info> {
-    #[account(mut,signer)]
-    owner : AccountInfo<'info>,
-
-    #[account(mut,owner=spl_token::id())]
-    source_collateral : AccountInfo<'info>,
+/// Loan-to-value must not exceed the liquidation threshold, which must not exceed 100%
+pub fn is_valid_borrow_rate(
+    max_borrow_rate_numerator : u64,
+    max_borrow_rate_denominator : u64,
+    liquidation_threshold_numerator : u64,
+    liquidation_threshold_denominator : u64,
+    ) -> bool {
+    max_borrow_rate_denominator != 0
+        && liquidation_threshold_denominator != 0
+        && max_borrow_rate_numerator as u128 * liquidation_threshold_denominator as u128
+            <= liquidation_threshold_numerator as u128 * max_borrow_rate_denominator as u128
+        && liquidation_threshold_numerator <= liquidation_threshold_denominator
+}
 
-    #[account(mut,owner=spl_token::id())]
-    dest_collateral : AccountInfo<'info>,
+/// Accrue interest on `reserve` and report the new totals when any slot elapsed
+pub fn accrue_reserve_interest(reserve : &mut ProgramAccount<Reserve>, current_slot : u64) -> ProgramResult {
+    let last_update_slot = reserve.last_update_slot;
+    reserve.accrue_interest(current_slot)?;
+    if reserve.last_update_slot != last_update_slot {
+        emit!(InterestAccrued{
+            reserve : reserve.key(),
+            cumulative_borrow_rate_wads : reserve.cumulative_borrow_rate_wads,
+            borrowed_amount_wads : reserve.borrowed_amount_wads,
+            available_liquidity : reserve.available_liquidity,
+            accumulated_protocol_fees : reserve.accumulated_protocol_fees,
+            slot : current_slot,
+        });
+    }
+    Ok(())
+}
 
-    #[account(mut)]
-    reserve : ProgramAccount<'info,Reserve>,
+/// Both oracle sets must be well formed and `accounts` must hold their price accounts in order,
+/// each owned by the program of its source
+pub fn check_oracle_accounts(
+    lending_market : &LendingMarket,
+    liquidity_oracles : &OracleSet,
+    collateral_oracles : &OracleSet,
+    accounts : &[AccountInfo],
+    ) -> ProgramResult {
+    if !liquidity_oracles.is_valid() || !collateral_oracles.is_valid() {
+        return Err(LendingError::InvalidOracleConfig.into());
+    }
+    let oracles = liquidity_oracles.oracles.iter().chain(collateral_oracles.oracles.iter());
+    if accounts.len() != liquidity_oracles.oracles.len() + collateral_oracles.oracles.len() {
+        return Err(LendingError::InvalidOracleConfig.into());
+    }
+    for (oracle, account) in oracles.zip(accounts) {
+        if oracle.address != *account.key || lending_market.oracle_program(oracle.source) != *account.owner {
+            return Err(LendingError::InvalidOracleConfig.into());
+        }
+    }
+    Ok(())
+}
 
-    #[account(mut,seeds=[reserve.key().as_ref(),(*owner.key).as_ref()], bump=obligation.bump, has_one=owner)]
-    obligation : ProgramAccount<'info,Obligation>,
+/// Origination fee is in basis points of the borrowed amount, host share a percentage of that fee
+pub fn is_valid_borrow_fee(borrow_fee_bps : u16, host_fee_percentage : u8) -> bool {
+    borrow_fee_bps as u64 <= BPS_DENOMINATOR && host_fee_percentage <= 100
+}
 
-    #[account(address=spl_token::id())]
-    token_program : AccountInfo<'info>,
+/// Bonus is a percentage added on top of the repaid value, close factor the
+/// largest percentage of the debt that can be repaid by a single liquidation
+pub fn is_valid_liquidation_config(liquidation_bonus : u8, liquidation_close_factor : u8) -> bool {
+    liquidation_bonus <= 100 && liquidation_close_factor > 0 && liquidation_close_factor <= 100
 }
 
-#[derive(Accounts)]
-#[instruction(_bump : u8)]
-pub struct InitObligation<'info> {
-    #[account(init, seeds=[reserve.key().as_ref(),(*owner.key).as_ref()], bump=_bump, payer=owner, space=8+OBLIGATION_SIZE)]
-    obligation : ProgramAccount<'info,Obligation>,
+/// Collateral released to a liquidator repaying `repay_amount` of liquidity
+pub fn liquidation_withdraw_amount(
+    repay_amount : u64,
+    repay_reserve : &Reserve,
+    withdraw_reserve : &Reserve,
+    ) -> std::result::Result<u64, ProgramError> {
+    // sized at the same prices that made the obligation liquidatable, so a momentary dip does not inflate the payout
+    let repay_value = repay_reserve.liquidity_value(repay_amount, PriceBasis::Liquidation)?;
+    let withdraw_value = repay_value.try_mul_div(100 + withdraw_reserve.liquidation_bonus as u128, 100)?;
+    withdraw_reserve.collateral_amount(withdraw_value, PriceBasis::Liquidation)
+}
 
-    #[account(mut,signer)]
-    owner : AccountInfo<'info>,
+/// `amount * price / 10^exponent`
+fn market_value(amount : u64, price : u128, exponent : u32) -> std::result::Result<Decimal, ProgramError> {
+    Decimal::from(amount).try_mul_div(price, ten_pow(exponent)?)
+}
 
-    reserve : AccountInfo<'info>,
+/// Inverse of `market_value`, the token amount worth `value`
+fn market_amount(value : Decimal, price : u128, exponent : u32) -> std::result::Result<u64, ProgramError> {
+    if price == 0 {
+        return Err(LendingError::InvalidOracleConfig.into());
+    }
+    value.try_mul_div(ten_pow(exponent)?, price)?.try_floor_u64()
+}
 
-    system_program : Program<'info,System>,
+#[event]
+pub struct LendingMarketInitialized {
+    pub lending_market : Pubkey,
+    pub owner : Pubkey,
+    pub config_delay : i64,
 }
 
-// seeds=[lending_market.key().as_ref(),(*liquidity_mint.key).as_ref(),(*collateral_mint.key).as_ref()], bump=bump ,
-// #[instruction(bump : u8,_max_borrow_rate : u8)]
-#[derive(Accounts)]
-#[instruction(_bump : u8, _max_borrow_rate : u64)]
-pub struct InitReserve<'info> {
-    #[account(init, 
-        seeds=[lending_market.key().as_ref(),(*collateral_mint.key).as_ref(),(*liquidity_mint.key).as_ref()],
-        bump=_bump,
-        payer=owner, space=8+RESERVE_SIZE)]
-    reserve : ProgramAccount<'info,Reserve>,
+/// Emitted when ownership is proposed, and with the default key when the proposal is cancelled
+#[event]
+pub struct PendingOwnerChanged {
+    pub lending_market : Pubkey,
+    pub pending_owner : Pubkey,
+}
 
-    #[account(mut)]
-    owner : Signer<'info>,
+#[event]
+pub struct OwnerChanged {
+    pub lending_market : Pubkey,
+    pub owner : Pubkey,
+}
 
-    #[account(mut,has_one=owner)]
-    lending_market : ProgramAccount<'info,LendingMarket>,
+#[event]
+pub struct RolesChanged {
+    pub lending_market : Pubkey,
+    pub risk_admin : Pubkey,
+    pub oracle_admin : Pubkey,
+    pub pause_guardian : Pubkey,
+    pub treasury : Pubkey,
+}
 
-    #[account(owner=spl_token::id())]
-    liquidity_mint : AccountInfo<'info>,
+#[event]
+pub struct ConfigDelayChanged {
+    pub lending_market : Pubkey,
+    pub config_delay : i64,
+}
 
-    #[account(owner=spl_token::id())]
-    liquidity_account : AccountInfo<'info>,
+#[event]
+pub struct FeeReceiverChanged {
+    pub lending_market : Pubkey,
+    pub fee_receiver : Pubkey,
+}
 
-    oracle_price : AccountInfo<'info>,
+#[event]
+pub struct ReserveInitialized {
+    pub lending_market : Pubkey,
+    pub reserve : Pubkey,
+    pub liquidity_mint : Pubkey,
+    pub collateral_mint : Pubkey,
+    pub config : ReserveConfig,
+}
 
-    #[account(owner=spl_token::id())]
-    collateral_mint : AccountInfo<'info>,
+#[event]
+pub struct ReserveConfigProposed {
+    pub reserve : Pubkey,
+    pub config : ReserveConfig,
+    pub executable_at : UnixTimestamp,
+}
 
-    #[account(owner=spl_token::id())]
-    collateral_account : AccountInfo<'info>,
+#[event]
+pub struct ReserveConfigExecuted {
+    pub reserve : Pubkey,
+    pub config : ReserveConfig,
+}
 
-    system_program : Program<'info,System>,
+#[event]
+pub struct ReserveConfigCancelled {
+    pub reserve : Pubkey,
 }
 
-#[derive(Accounts)]
-pub struct SetLendingMarketOwner<'info> {
-    #[account(mut, has_one=owner)]
-    lending_market : ProgramAccount<'info,LendingMarket>,
+#[event]
+pub struct ReserveOraclesProposed {
+    pub reserve : Pubkey,
+    pub liquidity_oracles : OracleSet,
+    pub collateral_oracles : OracleSet,
+    pub executable_at : UnixTimestamp,
+}
 
-    #[account(mut, signer)]
-    owner : AccountInfo<'info>,
+#[event]
+pub struct ReserveOraclesCancelled {
+    pub reserve : Pubkey,
+}
 
-    #[account(mut)]
-    new_owner : AccountInfo<'info>,
+#[event]
+pub struct ReserveOraclesChanged {
+    pub reserve : Pubkey,
+    pub liquidity_oracles : OracleSet,
+    pub collateral_oracles : OracleSet,
 }
 
-#[derive(Accounts)]
-pub struct ReserveLiveControl<'info> {
-    #[account(mut)]
-    reserve : ProgramAccount<'info,Reserve>,
+#[event]
+pub struct PriceOverrideChanged {
+    pub reserve : Pubkey,
+    pub price_override : bool,
+}
 
-    #[account(mut, signer)]
-    owner : AccountInfo<'info>,
+/// Reserve totals after interest was compounded, the borrowed amount includes the protocol fees
+#[event]
+pub struct InterestAccrued {
+    pub reserve : Pubkey,
+    pub cumulative_borrow_rate_wads : u128,
+    pub borrowed_amount_wads : u128,
+    pub available_liquidity : u64,
+    pub accumulated_protocol_fees : u64,
+    pub slot : u64,
+}
 
-    #[account(mut,has_one=owner)]
-    lending_market : ProgramAccount<'info,LendingMarket>,
+/// Spot prices stored on the reserve, from the oracles or pinned by the oracle admin
+#[event]
+pub struct PriceUpdated {
+    pub reserve : Pubkey,
+    pub liquidity_market_price : u128,
+    pub liquidity_market_price_decimals : u8,
+    pub collateral_market_price : u128,
+    pub collateral_market_price_decimals : u8,
+    pub slot : u64,
 }
 
-#[derive(Accounts)]
-pub struct InitLendingMarket<'info> {
-    #[account(init, payer=authority, space=8+LENDING_MARKET_SIZE)]
-    lending_market : ProgramAccount<'info,LendingMarket>,
+#[event]
+pub struct ReserveStatusChanged {
+    pub reserve : Pubkey,
+    pub authority : Pubkey,
+    pub status : ReserveStatus,
+}
 
-    #[account(mut)]
-    authority : Signer<'info>,
+#[event]
+pub struct ObligationInitialized {
+    pub lending_market : Pubkey,
+    pub obligation : Pubkey,
+    pub owner : Pubkey,
+}
 
-    oracle_program_id : AccountInfo<'info>,
+/// `deposited_amount` is the obligation's position in the reserve after the deposit
+#[event]
+pub struct CollateralDeposited {
+    pub reserve : Pubkey,
+    pub obligation : Pubkey,
+    pub owner : Pubkey,
+    pub amount : u64,
+    pub deposited_amount : u64,
+    pub total_collateral : u64,
+}
 
-    system_program : Program<'info,System>
+#[event]
+pub struct CollateralWithdrawn {
+    pub reserve : Pubkey,
+    pub obligation : Pubkey,
+    pub owner : Pubkey,
+    pub amount : u64,
+    pub deposited_amount : u64,
+    pub total_collateral : u64,
 }
 
-#[account]
-pub struct LendingMarket{
+/// `amount` is what the borrower received, the debt also grows by `borrow_fee`
+#[event]
+pub struct LiquidityBorrowed {
+    pub reserve : Pubkey,
+    pub obligation : Pubkey,
     pub owner : Pubkey,
-    pub oracle_program_id : Pubkey,
+    pub amount : u64,
+    pub borrow_fee : u64,
+    pub host_fee : u64,
+    pub borrowed_amount : u64,
+    pub total_liquidity : u64,
+    pub available_liquidity : u64,
 }
 
-#[account]
-pub struct Reserve{
-    pub is_live : bool,
-    pub lending_market : Pubkey,
-    pub liquidity_mint : Pubkey,
-    pub liquidity_account : Pubkey,
-    pub liquidity_oracle : Pubkey,
-    pub collateral_mint : Pubkey,
-    pub collateral_account : Pubkey,
+#[event]
+pub struct LiquidityRepaid {
+    pub reserve : Pubkey,
+    pub obligation : Pubkey,
+    pub owner : Pubkey,
+    pub amount : u64,
+    pub borrowed_amount : u64,
     pub total_liquidity : u64,
-    pub total_collateral : u64,
-    pub max_borrow_rate_numerator : u64,
-    pub max_borrow_rate_denominator : u64,
-    pub liquidity_market_price : u128,
-    pub liquidity_market_price_decimals : u8,
-    pub collateral_market_price : u128,
-    pub collateral_market_price_decimals : u8,
-    pub bump : u8,
+    pub available_liquidity : u64,
 }
 
-#[account]
-pub struct Obligation{
+/// Obligation positions and reserve totals are taken after the liquidation
+#[event]
+pub struct ObligationLiquidated {
+    pub obligation : Pubkey,
+    pub owner : Pubkey,
+    pub liquidator : Pubkey,
+    pub repay_reserve : Pubkey,
+    pub withdraw_reserve : Pubkey,
+    pub repay_amount : u64,
+    pub withdraw_amount : u64,
+    pub borrowed_amount : u64,
+    pub deposited_amount : u64,
+    pub repay_total_liquidity : u64,
+    pub repay_available_liquidity : u64,
+    pub withdraw_total_collateral : u64,
+}
+
+#[event]
+pub struct LiquidityDeposited {
     pub reserve : Pubkey,
     pub owner : Pubkey,
-    pub input_amount : u64,
-    pub output_amount : u64,
-    pub bump : u8,
+    pub liquidity_amount : u64,
+    pub share_amount : u64,
+    pub available_liquidity : u64,
+    pub share_mint_total_supply : u64,
+}
+
+#[event]
+pub struct LiquidityRedeemed {
+    pub reserve : Pubkey,
+    pub owner : Pubkey,
+    pub liquidity_amount : u64,
+    pub share_amount : u64,
+    pub available_liquidity : u64,
+    pub share_mint_total_supply : u64,
+}
+
+#[event]
+pub struct FlashLoanRepaid {
+    pub reserve : Pubkey,
+    pub receiver_program : Pubkey,
+    pub amount : u64,
+    pub fee : u64,
+    pub available_liquidity : u64,
+}
+
+#[event]
+pub struct ProtocolFeesCollected {
+    pub reserve : Pubkey,
+    pub destination : Pubkey,
+    pub amount : u64,
+    pub accumulated_protocol_fees : u64,
+    pub available_liquidity : u64,
+}
+
+#[event]
+pub struct ExcessCollateralWithdrawn {
+    pub reserve : Pubkey,
+    pub destination : Pubkey,
+    pub amount : u64,
+    /// collateral still held above `Reserve.total_collateral` after the withdrawal
+    pub remaining_excess : u64,
 }
 
 #[error]
@@ -793,6 +2618,12 @@ pub enum LendingError {
     #[msg("Token set authority failed")]
     TokenSetAuthorityFailed,
 
+    #[msg("Token mint to failed")]
+    TokenMintToFailed,
+
+    #[msg("Token burn failed")]
+    TokenBurnFailed,
+
     #[msg("Not enough liquidity")]
     NotEnoughLiquidity,
 
@@ -807,6 +2638,87 @@ pub enum LendingError {
 
     #[msg("Derived key invalid")]
     DerivedKeyInvalid,
+
+    #[msg("Invalid reserve config")]
+    InvalidReserveConfig,
+
+    #[msg("Obligation is healthy and cannot be liquidated")]
+    ObligationHealthy,
+
+    #[msg("Liquidation amount too small")]
+    LiquidationTooSmall,
+
+    #[msg("Not match share mint")]
+    NotMatchShareMint,
+
+    #[msg("Invalid amount")]
+    InvalidAmount,
+
+    #[msg("Obligation has no position in this reserve")]
+    ObligationReserveNotFound,
+
+    #[msg("Obligation reserve limit reached")]
+    ObligationReserveLimit,
+
+    #[msg("Reserve of an obligation position was not provided")]
+    ObligationReserveMissing,
+
+    #[msg("Invalid flash loan receiver program")]
+    InvalidFlashLoanReceiver,
+
+    #[msg("Flash loan was not repaid with its fee")]
+    FlashLoanNotRepaid,
+
+    #[msg("Reserve status does not allow this operation")]
+    ReservePaused,
+
+    #[msg("Oracle answer is older than the staleness threshold")]
+    StaleOracleAnswer,
+
+    #[msg("Not enough fresh oracle submissions")]
+    InsufficientOracleAnswers,
+
+    #[msg("Reserve prices must be refreshed in the current slot")]
+    ReserveStale,
+
+    #[msg("Manual prices require the reserve price override")]
+    PriceOverrideDisabled,
+
+    #[msg("Borrows, withdrawals and liquidations are suspended while a reserve price is overridden")]
+    PriceOverrideActive,
+
+    #[msg("Manual price deviates too far from the reserve price TWAP")]
+    PriceOverrideOutOfBounds,
+
+    #[msg("Oracle price is not trading")]
+    OracleNotTrading,
+
+    #[msg("Oracle confidence interval is too wide")]
+    OracleConfidenceTooWide,
+
+    #[msg("Oracle answers diverge beyond the allowed deviation")]
+    OracleDeviationTooWide,
+
+    #[msg("Token account is not owned by the lending market fee receiver")]
+    NotMatchFeeReceiver,
+
+    #[msg("Reserve config delay must not be negative or decrease")]
+    InvalidConfigDelay,
+
+    #[msg("Pending reserve config is still timelocked")]
+    ConfigNotReady,
+
+    #[msg("Signer does not hold the role required by this instruction")]
+    Unauthorized,
+
+    #[msg("Signer is not the pending lending market owner")]
+    NotPendingOwner,
+
+    #[msg("Deposit would exceed the reserve deposit limit")]
+    DepositLimitExceeded,
+
+    #[msg("Borrow would exceed the reserve borrow limit")]
+    BorrowLimitExceeded,
 }
 
 #[derive(Clone, Copy, Eq, PartialEq, BorshSerialize, BorshDeserialize, Default, Debug)]
@@ -825,6 +2737,34 @@ pub struct Aggregator {
     pub answer : Option<u128>,
 }
 
+/// Fresh (price, decimals) pair of a Chainlink aggregator account
+pub fn read_aggregator_price(oracle_price : &AccountInfo, now : UnixTimestamp) -> std::result::Result<(u128, u8), ProgramError> {
+    let data = oracle_price.data.borrow();
+    let aggregator : Aggregator = try_from_slice_unchecked(data.get(..4096).ok_or(LendingError::InvalidOracleConfig)?)?;
+    Ok((aggregator.fresh_answer(now)?, aggregator.config.decimals))
+}
+
+impl Aggregator {
+    /// Latest answer, rejected when it or too many of its submissions are older than the staleness threshold
+    pub fn fresh_answer(&self, now : UnixTimestamp) -> std::result::Result<u128, ProgramError> {
+        let answer = match self.answer {
+            Some(answer) => answer,
+            None => return Err(LendingError::InvalidOracleConfig.into()),
+        };
+        let staleness_threshold = self.config.staleness_threshold as UnixTimestamp;
+        if now.saturating_sub(self.updated_at) > staleness_threshold {
+            return Err(LendingError::StaleOracleAnswer.into());
+        }
+        let fresh_submissions = self.submissions.iter()
+            .filter(|submission| submission.0 != 0 && now.saturating_sub(submission.0) <= staleness_threshold)
+            .count();
+        if fresh_submissions < self.config.min_answer_threshold as usize {
+            return Err(LendingError::InsufficientOracleAnswers.into());
+        }
+        Ok(answer)
+    }
+}
+
 #[derive(Clone,Eq, PartialEq, BorshSerialize, BorshDeserialize, Default, Debug)]
 #[repr(C)]
 pub struct Config {
@@ -833,3 +2773,57 @@ pub struct Config {
     pub staleness_threshold : u8,
     pub decimals : u8,
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const NOW : UnixTimestamp = 1_000;
+
+    /// Answer of 145 confirmed by three of four submissions, with a 2 of 8 quorum and a 60 second threshold
+    fn aggregator() -> Aggregator {
+        let mut submissions = [Submission::default(); 8];
+        submissions[0] = Submission(NOW - 10, 145);
+        submissions[1] = Submission(NOW - 20, 146);
+        submissions[2] = Submission(NOW - 30, 144);
+        submissions[3] = Submission(NOW - 300, 120);
+        Aggregator{
+            is_initialize : true,
+            version : 1,
+            config : Config{ oracles : vec![], min_answer_threshold : 2, staleness_threshold : 60, decimals : 2 },
+            updated_at : NOW - 10,
+            owner : Pubkey::default(),
+            submissions,
+            answer : Some(145),
+        }
+    }
+
+    #[test]
+    fn accepts_fresh_answer_with_quorum() {
+        assert_eq!(aggregator().fresh_answer(NOW), Ok(145));
+    }
+
+    #[test]
+    fn rejects_stale_answer() {
+        let mut aggregator = aggregator();
+        aggregator.updated_at = NOW - 61;
+        assert_eq!(aggregator.fresh_answer(NOW), Err(LendingError::StaleOracleAnswer.into()));
+    }
+
+    #[test]
+    fn rejects_answer_with_too_few_fresh_submissions() {
+        let mut aggregator = aggregator();
+        aggregator.config.min_answer_threshold = 4;
+        assert_eq!(aggregator.fresh_answer(NOW), Err(LendingError::InsufficientOracleAnswers.into()));
+        // submissions that aged out no longer count towards the quorum
+        aggregator.config.min_answer_threshold = 2;
+        assert_eq!(aggregator.fresh_answer(NOW + 45), Err(LendingError::InsufficientOracleAnswers.into()));
+    }
+
+    #[test]
+    fn rejects_missing_answer() {
+        let mut aggregator = aggregator();
+        aggregator.answer = None;
+        assert_eq!(aggregator.fresh_answer(NOW), Err(LendingError::InvalidOracleConfig.into()));
+    }
+}
@@ -0,0 +1,300 @@
+use {
+    crate::{LendingError, BPS_DENOMINATOR, WAD},
+    anchor_lang::prelude::ProgramError,
+    std::convert::TryFrom,
+};
+
+pub trait TryAdd : Sized {
+    fn try_add(self, rhs : Self) -> Result<Self, ProgramError>;
+}
+
+pub trait TrySub : Sized {
+    fn try_sub(self, rhs : Self) -> Result<Self, ProgramError>;
+}
+
+pub trait TryMul<Rhs> : Sized {
+    fn try_mul(self, rhs : Rhs) -> Result<Self, ProgramError>;
+}
+
+pub trait TryDiv<Rhs> : Sized {
+    fn try_div(self, rhs : Rhs) -> Result<Self, ProgramError>;
+}
+
+impl TryAdd for u64 {
+    fn try_add(self, rhs : Self) -> Result<Self, ProgramError> {
+        self.checked_add(rhs).ok_or_else(overflow)
+    }
+}
+
+impl TrySub for u64 {
+    fn try_sub(self, rhs : Self) -> Result<Self, ProgramError> {
+        self.checked_sub(rhs).ok_or_else(overflow)
+    }
+}
+
+/// Unsigned fixed-point number scaled by WAD, used for token values and amounts in flight
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Decimal(u128);
+
+/// Unsigned fixed-point ratio scaled by WAD, used for interest rates and indexes
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Rate(u128);
+
+fn overflow() -> ProgramError {
+    LendingError::MathOverflow.into()
+}
+
+/// `10^exponent`, rejected when it does not fit in a u128
+pub fn ten_pow(exponent : u32) -> Result<u128, ProgramError> {
+    10u128.checked_pow(exponent).ok_or_else(overflow)
+}
+
+/// `a * b / c` with the product kept at full 256-bit width, rejected when the quotient does not fit in a u128
+pub fn try_mul_div(a : u128, b : u128, c : u128) -> Result<u128, ProgramError> {
+    if c == 0 {
+        return Err(overflow());
+    }
+    if let Some(product) = a.checked_mul(b) {
+        return Ok(product / c);
+    }
+    let (high, low) = full_mul(a, b);
+    if high >= c {
+        return Err(overflow());
+    }
+    // schoolbook division of the 256-bit product, the remainder always stays below `c`
+    let mut remainder = high;
+    let mut quotient = 0u128;
+    for bit in (0..128).rev() {
+        let carry = remainder >> 127;
+        remainder = (remainder << 1) | ((low >> bit) & 1);
+        quotient <<= 1;
+        if carry == 1 || remainder >= c {
+            remainder = remainder.wrapping_sub(c);
+            quotient |= 1;
+        }
+    }
+    Ok(quotient)
+}
+
+/// (high, low) halves of the 256-bit product `a * b`
+fn full_mul(a : u128, b : u128) -> (u128, u128) {
+    const MASK : u128 = u64::MAX as u128;
+    let (a_high, a_low) = (a >> 64, a & MASK);
+    let (b_high, b_low) = (b >> 64, b & MASK);
+    let low_low = a_low * b_low;
+    let low_high = a_low * b_high;
+    let high_low = a_high * b_low;
+    let high_high = a_high * b_high;
+    let middle = (low_low >> 64) + (low_high & MASK) + (high_low & MASK);
+    let low = (low_low & MASK) | (middle << 64);
+    let high = high_high + (low_high >> 64) + (high_low >> 64) + (middle >> 64);
+    (high, low)
+}
+
+impl Decimal {
+    pub fn zero() -> Self {
+        Decimal(0)
+    }
+
+    pub fn one() -> Self {
+        Decimal(WAD)
+    }
+
+    pub fn from_scaled_val(scaled_val : u128) -> Self {
+        Decimal(scaled_val)
+    }
+
+    pub fn to_scaled_val(self) -> u128 {
+        self.0
+    }
+
+    pub fn from_percent(percent : u8) -> Self {
+        Decimal(percent as u128 * WAD / 100)
+    }
+
+    pub fn from_bps(bps : u16) -> Self {
+        Decimal(bps as u128 * WAD / BPS_DENOMINATOR as u128)
+    }
+
+    /// `value * 10^-exponent`
+    pub fn try_from_scaled(value : u128, exponent : u32) -> Result<Self, ProgramError> {
+        if exponent <= 18 {
+            Ok(Decimal(value.checked_mul(ten_pow(18 - exponent)?).ok_or_else(overflow)?))
+        } else {
+            Ok(Decimal(value / ten_pow(exponent - 18)?))
+        }
+    }
+
+    /// `self * numerator / denominator` without overflowing on the intermediate product
+    pub fn try_mul_div(self, numerator : u128, denominator : u128) -> Result<Self, ProgramError> {
+        Ok(Decimal(try_mul_div(self.0, numerator, denominator)?))
+    }
+
+    pub fn try_floor_u64(self) -> Result<u64, ProgramError> {
+        u64::try_from(self.0 / WAD).map_err(|_| overflow())
+    }
+
+    pub fn try_ceil_u64(self) -> Result<u64, ProgramError> {
+        let ceiled = self.0.checked_add(WAD - 1).ok_or_else(overflow)? / WAD;
+        u64::try_from(ceiled).map_err(|_| overflow())
+    }
+}
+
+impl From<u64> for Decimal {
+    fn from(amount : u64) -> Self {
+        Decimal(amount as u128 * WAD)
+    }
+}
+
+impl From<Rate> for Decimal {
+    fn from(rate : Rate) -> Self {
+        Decimal(rate.0)
+    }
+}
+
+impl TryAdd for Decimal {
+    fn try_add(self, rhs : Self) -> Result<Self, ProgramError> {
+        Ok(Decimal(self.0.checked_add(rhs.0).ok_or_else(overflow)?))
+    }
+}
+
+impl TrySub for Decimal {
+    fn try_sub(self, rhs : Self) -> Result<Self, ProgramError> {
+        Ok(Decimal(self.0.checked_sub(rhs.0).ok_or_else(overflow)?))
+    }
+}
+
+impl TryMul<u64> for Decimal {
+    fn try_mul(self, rhs : u64) -> Result<Self, ProgramError> {
+        Ok(Decimal(self.0.checked_mul(rhs as u128).ok_or_else(overflow)?))
+    }
+}
+
+impl TryMul<Decimal> for Decimal {
+    fn try_mul(self, rhs : Decimal) -> Result<Self, ProgramError> {
+        Ok(Decimal(try_mul_div(self.0, rhs.0, WAD)?))
+    }
+}
+
+impl TryMul<Rate> for Decimal {
+    fn try_mul(self, rhs : Rate) -> Result<Self, ProgramError> {
+        self.try_mul(Decimal::from(rhs))
+    }
+}
+
+impl TryDiv<u64> for Decimal {
+    fn try_div(self, rhs : u64) -> Result<Self, ProgramError> {
+        Ok(Decimal(self.0.checked_div(rhs as u128).ok_or_else(overflow)?))
+    }
+}
+
+impl TryDiv<Decimal> for Decimal {
+    fn try_div(self, rhs : Decimal) -> Result<Self, ProgramError> {
+        Ok(Decimal(try_mul_div(self.0, WAD, rhs.0)?))
+    }
+}
+
+impl Rate {
+    pub fn zero() -> Self {
+        Rate(0)
+    }
+
+    pub fn one() -> Self {
+        Rate(WAD)
+    }
+
+    pub fn from_scaled_val(scaled_val : u128) -> Self {
+        Rate(scaled_val)
+    }
+
+    pub fn to_scaled_val(self) -> u128 {
+        self.0
+    }
+
+    pub fn from_percent(percent : u8) -> Self {
+        Rate(percent as u128 * WAD / 100)
+    }
+
+    /// `self^exp` by repeated squaring
+    pub fn try_pow(self, mut exp : u64) -> Result<Self, ProgramError> {
+        let mut base = self;
+        let mut result = Rate::one();
+        while exp > 0 {
+            if exp & 1 == 1 {
+                result = result.try_mul(base)?;
+            }
+            exp >>= 1;
+            if exp > 0 {
+                base = base.try_mul(base)?;
+            }
+        }
+        Ok(result)
+    }
+}
+
+impl TryAdd for Rate {
+    fn try_add(self, rhs : Self) -> Result<Self, ProgramError> {
+        Ok(Rate(self.0.checked_add(rhs.0).ok_or_else(overflow)?))
+    }
+}
+
+impl TrySub for Rate {
+    fn try_sub(self, rhs : Self) -> Result<Self, ProgramError> {
+        Ok(Rate(self.0.checked_sub(rhs.0).ok_or_else(overflow)?))
+    }
+}
+
+impl TryMul<u64> for Rate {
+    fn try_mul(self, rhs : u64) -> Result<Self, ProgramError> {
+        Ok(Rate(self.0.checked_mul(rhs as u128).ok_or_else(overflow)?))
+    }
+}
+
+impl TryMul<Rate> for Rate {
+    fn try_mul(self, rhs : Rate) -> Result<Self, ProgramError> {
+        Ok(Rate(try_mul_div(self.0, rhs.0, WAD)?))
+    }
+}
+
+impl TryDiv<u64> for Rate {
+    fn try_div(self, rhs : u64) -> Result<Self, ProgramError> {
+        Ok(Rate(self.0.checked_div(rhs as u128).ok_or_else(overflow)?))
+    }
+}
+
+impl TryDiv<Rate> for Rate {
+    fn try_div(self, rhs : Rate) -> Result<Self, ProgramError> {
+        Ok(Rate(try_mul_div(self.0, WAD, rhs.0)?))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn mul_div_keeps_wide_products() {
+        assert_eq!(try_mul_div(u128::MAX, WAD, WAD), Ok(u128::MAX));
+        assert_eq!(try_mul_div(u128::MAX / 3, 6, 4), Ok(u128::MAX / 2));
+        assert_eq!(try_mul_div(u128::MAX, 2, 1), Err(LendingError::MathOverflow.into()));
+        assert_eq!(try_mul_div(1, 1, 0), Err(LendingError::MathOverflow.into()));
+    }
+
+    #[test]
+    fn decimal_arithmetic_is_checked() {
+        let large = Decimal::from(u64::MAX);
+        assert_eq!(large.try_mul(Decimal::one()), Ok(large));
+        assert_eq!(large.try_div(large), Ok(Decimal::one()));
+        assert_eq!(Decimal::zero().try_sub(Decimal::one()), Err(LendingError::MathOverflow.into()));
+        assert_eq!(large.try_mul(u64::MAX), Err(LendingError::MathOverflow.into()));
+        assert_eq!(Decimal::from_scaled_val(WAD + 1).try_ceil_u64(), Ok(2));
+        assert_eq!(Decimal::from_bps(25).try_mul(10_000u64).and_then(Decimal::try_floor_u64), Ok(25));
+    }
+
+    #[test]
+    fn rate_compounds() {
+        let rate = Rate::from_percent(10).try_add(Rate::one()).unwrap();
+        assert_eq!(rate.try_pow(2), Ok(Rate::from_scaled_val(1_210_000_000_000_000_000)));
+        assert_eq!(rate.try_pow(0), Ok(Rate::one()));
+    }
+}
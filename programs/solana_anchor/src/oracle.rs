@@ -0,0 +1,163 @@
+use {
+    crate::{read_aggregator_price, pyth::read_pyth_price, switchboard::read_switchboard_price, LendingError, BPS_DENOMINATOR, WAD},
+    anchor_lang::prelude::{AccountInfo, AnchorDeserialize, AnchorSerialize, Clock, ProgramError, Pubkey},
+};
+
+/// Layout of the price account a reserve reads
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum OracleSource {
+    Chainlink,
+    Pyth,
+    Switchboard,
+}
+
+/// Fresh (price, decimals) pair of `oracle_price` decoded as `source`
+pub fn read_oracle_price(
+    source : OracleSource,
+    oracle_price : &AccountInfo,
+    clock : &Clock,
+    ) -> Result<(u128, u8), ProgramError> {
+    match source {
+        OracleSource::Chainlink => read_aggregator_price(oracle_price, clock.unix_timestamp),
+        OracleSource::Pyth => read_pyth_price(&oracle_price.data.borrow(), clock.slot),
+        OracleSource::Switchboard => read_switchboard_price(&oracle_price.data.borrow(), clock.slot),
+    }
+}
+
+pub const MAX_RESERVE_ORACLES : usize = 3;
+pub const PRICE_ORACLE_SIZE : usize = 32+1;
+pub const ORACLE_SET_SIZE : usize = 4+MAX_RESERVE_ORACLES*PRICE_ORACLE_SIZE+1+2;
+
+/// Price account read by a reserve and the layout to decode it with
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub struct PriceOracle {
+    pub address : Pubkey,
+    pub source : OracleSource,
+}
+
+/// How the answers of an oracle set are combined into one price
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PriceAggregation {
+    /// median of every oracle that answered
+    Median,
+    /// first oracle in order that answered
+    Fallback,
+}
+
+/// Oracles pricing one side of a reserve
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Debug, PartialEq, Eq)]
+pub struct OracleSet {
+    pub oracles : Vec<PriceOracle>,
+    pub aggregation : PriceAggregation,
+    /// answers further than this from the chosen price count as diverging
+    pub max_deviation_bps : u16,
+}
+
+impl Default for OracleSet {
+    fn default() -> Self {
+        OracleSet{
+            oracles : Vec::new(),
+            aggregation : PriceAggregation::Median,
+            max_deviation_bps : 0,
+        }
+    }
+}
+
+impl OracleSet {
+    pub fn is_valid(&self) -> bool {
+        !self.oracles.is_empty()
+            && self.oracles.len() <= MAX_RESERVE_ORACLES
+            && self.max_deviation_bps as u64 <= BPS_DENOMINATOR
+    }
+
+    /// Combined (price, decimals) pair of the set, `oracle_prices` must follow the order of `oracles`.
+    /// Oracles that fail to answer are skipped, the result is rejected unless a majority of
+    /// the answers lies within `max_deviation_bps` of the chosen price.
+    pub fn read_price(&self, oracle_prices : &[AccountInfo], clock : &Clock) -> Result<(u128, u8), ProgramError> {
+        if oracle_prices.len() != self.oracles.len() {
+            return Err(LendingError::InvalidOracleConfig.into());
+        }
+        let mut answers = Vec::with_capacity(self.oracles.len());
+        let mut first_error = None;
+        for (oracle, oracle_price) in self.oracles.iter().zip(oracle_prices) {
+            if oracle.address != *oracle_price.key {
+                return Err(LendingError::InvalidOracleConfig.into());
+            }
+            match read_oracle_price(oracle.source, oracle_price, clock) {
+                Ok(answer) => answers.push(answer),
+                Err(error) => { first_error.get_or_insert(error); },
+            }
+        }
+        match first_error {
+            Some(error) if answers.is_empty() => Err(error),
+            _ => aggregate_prices(&answers, self.aggregation, self.max_deviation_bps),
+        }
+    }
+}
+
+/// Pick one of `answers` according to `aggregation` and check the others agree with it
+pub fn aggregate_prices(answers : &[(u128, u8)], aggregation : PriceAggregation, max_deviation_bps : u16) -> Result<(u128, u8), ProgramError> {
+    let mut normalized = answers.iter()
+        .map(|&(price, decimals)| normalize_price(price, decimals).map(|value| (value, (price, decimals))))
+        .collect::<Result<Vec<_>, _>>()?;
+    let (chosen_value, chosen) = match aggregation {
+        PriceAggregation::Fallback => *normalized.first().ok_or(LendingError::InsufficientOracleAnswers)?,
+        PriceAggregation::Median => {
+            if normalized.is_empty() {
+                return Err(LendingError::InsufficientOracleAnswers.into());
+            }
+            normalized.sort_by_key(|&(value, _)| value);
+            normalized[(normalized.len() - 1) / 2]
+        },
+    };
+
+    let mut agreeing = 0;
+    for &(value, _) in normalized.iter() {
+        let deviation = value.max(chosen_value) - value.min(chosen_value);
+        let within_bound = deviation.checked_mul(BPS_DENOMINATOR as u128).ok_or(LendingError::MathOverflow)?
+            <= chosen_value.checked_mul(max_deviation_bps as u128).ok_or(LendingError::MathOverflow)?;
+        if within_bound {
+            agreeing += 1;
+        }
+    }
+    if agreeing * 2 <= normalized.len() {
+        return Err(LendingError::OracleDeviationTooWide.into());
+    }
+    Ok(chosen)
+}
+
+/// Price scaled by WAD so answers with different decimals can be compared
+pub fn normalize_price(price : u128, decimals : u8) -> Result<u128, ProgramError> {
+    let scale = 10u128.checked_pow(decimals as u32).ok_or(LendingError::MathOverflow)?;
+    Ok(price.checked_mul(WAD).ok_or(LendingError::MathOverflow)? / scale)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn median_ignores_single_outlier() {
+        let answers = [(10_100, 2), (100_000, 3), (25_000, 2)];
+        assert_eq!(aggregate_prices(&answers, PriceAggregation::Median, 200), Ok((10_100, 2)));
+    }
+
+    #[test]
+    fn fallback_prefers_first_answer() {
+        let answers = [(100_000, 3), (10_050, 2), (9_980, 2)];
+        assert_eq!(aggregate_prices(&answers, PriceAggregation::Fallback, 100), Ok((100_000, 3)));
+    }
+
+    #[test]
+    fn rejects_diverging_answers() {
+        let answers = [(10_000, 2), (12_000, 2)];
+        assert_eq!(
+            aggregate_prices(&answers, PriceAggregation::Fallback, 500),
+            Err(LendingError::OracleDeviationTooWide.into()),
+        );
+        assert_eq!(
+            aggregate_prices(&[], PriceAggregation::Median, 500),
+            Err(LendingError::InsufficientOracleAnswers.into()),
+        );
+    }
+}
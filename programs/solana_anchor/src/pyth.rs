@@ -0,0 +1,148 @@
+use {
+    crate::LendingError,
+    anchor_lang::prelude::ProgramError,
+    std::convert::TryInto,
+};
+
+pub const PYTH_MAGIC : u32 = 0xa1b2c3d4;
+pub const PYTH_VERSION : u32 = 2;
+pub const PYTH_ACCOUNT_TYPE_PRICE : u32 = 3;
+pub const PYTH_STATUS_TRADING : u32 = 1;
+/// Slots after which a published aggregate is considered stale
+pub const PYTH_MAX_SLOTS_ELAPSED : u64 = 60;
+/// Widest accepted confidence interval, in basis points of the price
+pub const PYTH_MAX_CONFIDENCE_BPS : u128 = 200;
+
+const MAGIC_OFFSET : usize = 0;
+const VERSION_OFFSET : usize = 4;
+const ACCOUNT_TYPE_OFFSET : usize = 8;
+const EXPONENT_OFFSET : usize = 20;
+const AGGREGATE_PRICE_OFFSET : usize = 208;
+const AGGREGATE_CONF_OFFSET : usize = 216;
+const AGGREGATE_STATUS_OFFSET : usize = 224;
+const AGGREGATE_PUBLISH_SLOT_OFFSET : usize = 232;
+const PRICE_ACCOUNT_HEADER_SIZE : usize = 240;
+
+/// Aggregate price of a Pyth v2 price account
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct PythPrice {
+    pub price : i64,
+    pub conf : u64,
+    pub expo : i32,
+    pub status : u32,
+    pub publish_slot : u64,
+}
+
+pub fn parse_pyth_price(data : &[u8]) -> Result<PythPrice, ProgramError> {
+    if data.len() < PRICE_ACCOUNT_HEADER_SIZE {
+        return Err(LendingError::InvalidOracleConfig.into());
+    }
+    if read_u32(data, MAGIC_OFFSET) != PYTH_MAGIC
+        || read_u32(data, VERSION_OFFSET) != PYTH_VERSION
+        || read_u32(data, ACCOUNT_TYPE_OFFSET) != PYTH_ACCOUNT_TYPE_PRICE {
+        return Err(LendingError::InvalidOracleConfig.into());
+    }
+    Ok(PythPrice{
+        price : read_u64(data, AGGREGATE_PRICE_OFFSET) as i64,
+        conf : read_u64(data, AGGREGATE_CONF_OFFSET),
+        expo : read_u32(data, EXPONENT_OFFSET) as i32,
+        status : read_u32(data, AGGREGATE_STATUS_OFFSET),
+        publish_slot : read_u64(data, AGGREGATE_PUBLISH_SLOT_OFFSET),
+    })
+}
+
+impl PythPrice {
+    /// (price, decimals) pair, rejected when not trading, stale or too uncertain
+    pub fn market_price(&self, current_slot : u64) -> Result<(u128, u8), ProgramError> {
+        if self.status != PYTH_STATUS_TRADING {
+            return Err(LendingError::OracleNotTrading.into());
+        }
+        if current_slot.saturating_sub(self.publish_slot) > PYTH_MAX_SLOTS_ELAPSED {
+            return Err(LendingError::StaleOracleAnswer.into());
+        }
+        if self.price <= 0 {
+            return Err(LendingError::InvalidOracleConfig.into());
+        }
+        let price = self.price as u128;
+        if (self.conf as u128) * 10_000 > price * PYTH_MAX_CONFIDENCE_BPS {
+            return Err(LendingError::OracleConfidenceTooWide.into());
+        }
+        if self.expo >= 0 {
+            let price = 10u128.checked_pow(self.expo as u32)
+                .and_then(|scale| price.checked_mul(scale))
+                .ok_or(LendingError::MathOverflow)?;
+            Ok((price, 0))
+        } else {
+            let decimals = self.expo.checked_neg().and_then(|decimals| decimals.try_into().ok())
+                .ok_or(LendingError::InvalidOracleConfig)?;
+            Ok((price, decimals))
+        }
+    }
+}
+
+/// Fresh (price, decimals) pair of a Pyth price account
+pub fn read_pyth_price(data : &[u8], current_slot : u64) -> Result<(u128, u8), ProgramError> {
+    parse_pyth_price(data)?.market_price(current_slot)
+}
+
+fn read_u32(data : &[u8], offset : usize) -> u32 {
+    u32::from_le_bytes(data[offset..offset + 4].try_into().unwrap())
+}
+
+fn read_u64(data : &[u8], offset : usize) -> u64 {
+    u64::from_le_bytes(data[offset..offset + 8].try_into().unwrap())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// SOL/USD-style price account encoded with the `PriceAccount` struct of pyth-sdk-solana 0.8.0
+    const TRADING_PRICE_ACCOUNT : &[u8] = include_bytes!("../fixtures/pyth_price_trading.bin");
+    /// The same account with its aggregate halted
+    const HALTED_PRICE_ACCOUNT : &[u8] = include_bytes!("../fixtures/pyth_price_halted.bin");
+    /// Product account, which shares the header but is not a price
+    const PRODUCT_ACCOUNT : &[u8] = include_bytes!("../fixtures/pyth_product.bin");
+
+    fn trading_price() -> PythPrice {
+        PythPrice{ price : 14_523_000_000, conf : 7_250_000, expo : -8, status : PYTH_STATUS_TRADING, publish_slot : 110_000_000 }
+    }
+
+    #[test]
+    fn parses_aggregate_price() {
+        assert_eq!(parse_pyth_price(TRADING_PRICE_ACCOUNT), Ok(trading_price()));
+        assert_eq!(read_pyth_price(TRADING_PRICE_ACCOUNT, 110_000_010), Ok((14_523_000_000, 8)));
+    }
+
+    #[test]
+    fn rejects_halted_price() {
+        assert_eq!(parse_pyth_price(HALTED_PRICE_ACCOUNT).map(|price| price.status), Ok(2));
+        assert_eq!(read_pyth_price(HALTED_PRICE_ACCOUNT, 110_000_000), Err(LendingError::OracleNotTrading.into()));
+    }
+
+    #[test]
+    fn rejects_other_accounts() {
+        assert_eq!(parse_pyth_price(PRODUCT_ACCOUNT), Err(LendingError::InvalidOracleConfig.into()));
+        assert_eq!(parse_pyth_price(&TRADING_PRICE_ACCOUNT[..100]), Err(LendingError::InvalidOracleConfig.into()));
+    }
+
+    #[test]
+    fn rejects_stale_price() {
+        assert_eq!(
+            trading_price().market_price(110_000_000 + PYTH_MAX_SLOTS_ELAPSED + 1),
+            Err(LendingError::StaleOracleAnswer.into()),
+        );
+    }
+
+    #[test]
+    fn rejects_wide_confidence() {
+        let price = PythPrice{ conf : 290_460_001, ..trading_price() };
+        assert_eq!(price.market_price(110_000_000), Err(LendingError::OracleConfidenceTooWide.into()));
+    }
+
+    #[test]
+    fn scales_positive_exponent() {
+        let price = PythPrice{ price : 42, conf : 0, expo : 3, ..trading_price() };
+        assert_eq!(price.market_price(110_000_000), Ok((42_000, 0)));
+    }
+}
@@ -0,0 +1,114 @@
+use {
+    crate::math::*,
+    anchor_lang::prelude::{AnchorDeserialize, AnchorSerialize, ProgramError},
+};
+
+pub const INTEREST_RATE_CONFIG_SIZE : usize = 1+1+1+1;
+
+/// Kinked borrow rate curve, every value is a percentage
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, Default, Debug, PartialEq, Eq)]
+pub struct InterestRateConfig {
+    /// utilization at which the curve switches slope
+    pub optimal_utilization_rate : u8,
+    /// APR at 0% utilization
+    pub min_borrow_rate : u8,
+    /// APR at the optimal utilization
+    pub optimal_borrow_rate : u8,
+    /// APR at 100% utilization
+    pub max_borrow_rate : u8,
+}
+
+impl InterestRateConfig {
+    pub fn is_valid(&self) -> bool {
+        self.optimal_utilization_rate <= 100
+            && self.min_borrow_rate <= self.optimal_borrow_rate
+            && self.optimal_borrow_rate <= self.max_borrow_rate
+    }
+}
+
+/// borrowed / (available + borrowed)
+pub fn utilization_rate(borrowed_amount : u64, available_amount : u64) -> Result<Rate, ProgramError> {
+    let total_supply = Decimal::from(borrowed_amount).try_add(Decimal::from(available_amount))?;
+    if total_supply == Decimal::zero() {
+        return Ok(Rate::zero());
+    }
+    let utilization = Decimal::from(borrowed_amount).try_div(total_supply)?;
+    Ok(Rate::from_scaled_val(utilization.to_scaled_val()))
+}
+
+/// Annual borrow rate for the given utilization
+pub fn borrow_rate(config : &InterestRateConfig, utilization_rate : Rate) -> Result<Rate, ProgramError> {
+    let optimal_utilization_rate = Rate::from_percent(config.optimal_utilization_rate);
+    let min_rate = Rate::from_percent(config.min_borrow_rate);
+    let optimal_rate = Rate::from_percent(config.optimal_borrow_rate);
+    let max_rate = Rate::from_percent(config.max_borrow_rate);
+
+    if config.optimal_utilization_rate == 100 || utilization_rate < optimal_utilization_rate {
+        if optimal_utilization_rate == Rate::zero() {
+            return Ok(min_rate);
+        }
+        let normalized_rate = utilization_rate.try_div(optimal_utilization_rate)?;
+        min_rate.try_add(normalized_rate.try_mul(optimal_rate.try_sub(min_rate)?)?)
+    } else {
+        let utilization_range = Rate::one().try_sub(optimal_utilization_rate)?;
+        let excess_utilization = utilization_rate.min(Rate::one()).try_sub(optimal_utilization_rate)?;
+        let normalized_rate = excess_utilization.try_div(utilization_range)?;
+        optimal_rate.try_add(normalized_rate.try_mul(max_rate.try_sub(optimal_rate)?)?)
+    }
+}
+
+/// Annual borrow rate of a reserve with the given balances
+pub fn current_borrow_rate(config : &InterestRateConfig, borrowed_amount : u64, available_amount : u64) -> Result<Rate, ProgramError> {
+    borrow_rate(config, utilization_rate(borrowed_amount, available_amount)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(optimal_utilization_rate : u8) -> InterestRateConfig {
+        InterestRateConfig{
+            optimal_utilization_rate,
+            min_borrow_rate : 2,
+            optimal_borrow_rate : 10,
+            max_borrow_rate : 100,
+        }
+    }
+
+    fn percent(value : u8) -> Rate {
+        Rate::from_percent(value)
+    }
+
+    #[test]
+    fn utilization_of_empty_reserve_is_zero() {
+        assert_eq!(utilization_rate(0, 0), Ok(Rate::zero()));
+        assert_eq!(utilization_rate(1, 3), Ok(percent(25)));
+        assert_eq!(utilization_rate(5, 0), Ok(Rate::one()));
+    }
+
+    #[test]
+    fn curve_follows_both_slopes() {
+        let config = config(80);
+        assert_eq!(borrow_rate(&config, Rate::zero()), Ok(percent(2)));
+        assert_eq!(borrow_rate(&config, percent(79)), Ok(Rate::from_scaled_val(99_000_000_000_000_000)));
+        assert_eq!(borrow_rate(&config, percent(80)), Ok(percent(10)));
+        assert_eq!(borrow_rate(&config, percent(90)), Ok(percent(55)));
+        assert_eq!(borrow_rate(&config, Rate::one()), Ok(percent(100)));
+    }
+
+    #[test]
+    fn zero_optimal_utilization_uses_only_the_upper_slope() {
+        let config = config(0);
+        assert_eq!(borrow_rate(&config, Rate::zero()), Ok(percent(10)));
+        assert_eq!(borrow_rate(&config, percent(50)), Ok(percent(55)));
+        assert_eq!(borrow_rate(&config, Rate::one()), Ok(percent(100)));
+    }
+
+    #[test]
+    fn full_optimal_utilization_uses_only_the_lower_slope() {
+        let config = config(100);
+        assert_eq!(borrow_rate(&config, Rate::zero()), Ok(percent(2)));
+        assert_eq!(borrow_rate(&config, percent(50)), Ok(percent(6)));
+        assert_eq!(borrow_rate(&config, Rate::one()), Ok(percent(10)));
+    }
+}
@@ -0,0 +1,107 @@
+use {
+    crate::LendingError,
+    anchor_lang::prelude::ProgramError,
+    std::convert::TryInto,
+};
+
+/// Anchor discriminator of a Switchboard v2 `AggregatorAccountData`
+pub const SWITCHBOARD_AGGREGATOR_DISCRIMINATOR : [u8; 8] = [217, 230, 65, 101, 201, 162, 27, 125];
+/// Slots after which the latest confirmed round is considered stale
+pub const SWITCHBOARD_MAX_SLOTS_ELAPSED : u64 = 60;
+
+const MIN_ORACLE_RESULTS_OFFSET : usize = 236;
+const ROUND_NUM_SUCCESS_OFFSET : usize = 341;
+const ROUND_OPEN_SLOT_OFFSET : usize = 350;
+const ROUND_RESULT_MANTISSA_OFFSET : usize = 366;
+const ROUND_RESULT_SCALE_OFFSET : usize = 382;
+const AGGREGATOR_HEADER_SIZE : usize = 386;
+
+/// Latest confirmed round of a Switchboard aggregator
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct SwitchboardResult {
+    pub mantissa : i128,
+    pub scale : u32,
+    pub num_success : u32,
+    pub min_oracle_results : u32,
+    pub round_open_slot : u64,
+}
+
+pub fn parse_switchboard_result(data : &[u8]) -> Result<SwitchboardResult, ProgramError> {
+    if data.len() < AGGREGATOR_HEADER_SIZE || data[..8] != SWITCHBOARD_AGGREGATOR_DISCRIMINATOR {
+        return Err(LendingError::InvalidOracleConfig.into());
+    }
+    Ok(SwitchboardResult{
+        mantissa : i128::from_le_bytes(data[ROUND_RESULT_MANTISSA_OFFSET..ROUND_RESULT_MANTISSA_OFFSET + 16].try_into().unwrap()),
+        scale : read_u32(data, ROUND_RESULT_SCALE_OFFSET),
+        num_success : read_u32(data, ROUND_NUM_SUCCESS_OFFSET),
+        min_oracle_results : read_u32(data, MIN_ORACLE_RESULTS_OFFSET),
+        round_open_slot : u64::from_le_bytes(data[ROUND_OPEN_SLOT_OFFSET..ROUND_OPEN_SLOT_OFFSET + 8].try_into().unwrap()),
+    })
+}
+
+impl SwitchboardResult {
+    /// (price, decimals) pair, rejected when stale or confirmed by too few oracles
+    pub fn market_price(&self, current_slot : u64) -> Result<(u128, u8), ProgramError> {
+        if current_slot.saturating_sub(self.round_open_slot) > SWITCHBOARD_MAX_SLOTS_ELAPSED {
+            return Err(LendingError::StaleOracleAnswer.into());
+        }
+        if self.num_success < self.min_oracle_results {
+            return Err(LendingError::InsufficientOracleAnswers.into());
+        }
+        if self.mantissa <= 0 {
+            return Err(LendingError::InvalidOracleConfig.into());
+        }
+        let decimals : u8 = self.scale.try_into().map_err(|_| LendingError::InvalidOracleConfig)?;
+        Ok((self.mantissa as u128, decimals))
+    }
+}
+
+/// Fresh (price, decimals) pair of a Switchboard aggregator account
+pub fn read_switchboard_price(data : &[u8], current_slot : u64) -> Result<(u128, u8), ProgramError> {
+    parse_switchboard_result(data)?.market_price(current_slot)
+}
+
+fn read_u32(data : &[u8], offset : usize) -> u32 {
+    u32::from_le_bytes(data[offset..offset + 4].try_into().unwrap())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// SOL/USD-style `AggregatorAccountData`, packed field by field from the Switchboard v2 struct
+    /// with a confirmed round at slot 110000000 and a newer round still open
+    const AGGREGATOR_ACCOUNT : &[u8] = include_bytes!("../fixtures/switchboard_aggregator.bin");
+
+    fn confirmed_round() -> SwitchboardResult {
+        SwitchboardResult{ mantissa : 145_230_000_000, scale : 9, num_success : 4, min_oracle_results : 3, round_open_slot : 110_000_000 }
+    }
+
+    #[test]
+    fn parses_latest_confirmed_round() {
+        assert_eq!(AGGREGATOR_ACCOUNT.len(), 3851);
+        assert_eq!(parse_switchboard_result(AGGREGATOR_ACCOUNT), Ok(confirmed_round()));
+        assert_eq!(read_switchboard_price(AGGREGATOR_ACCOUNT, 110_000_020), Ok((145_230_000_000, 9)));
+    }
+
+    #[test]
+    fn rejects_stale_round() {
+        assert_eq!(
+            read_switchboard_price(AGGREGATOR_ACCOUNT, 110_000_000 + SWITCHBOARD_MAX_SLOTS_ELAPSED + 1),
+            Err(LendingError::StaleOracleAnswer.into()),
+        );
+    }
+
+    #[test]
+    fn rejects_insufficient_results() {
+        let result = SwitchboardResult{ num_success : 2, ..confirmed_round() };
+        assert_eq!(result.market_price(110_000_000), Err(LendingError::InsufficientOracleAnswers.into()));
+    }
+
+    #[test]
+    fn rejects_other_accounts() {
+        let pyth_price_account : &[u8] = include_bytes!("../fixtures/pyth_price_trading.bin");
+        assert_eq!(parse_switchboard_result(pyth_price_account), Err(LendingError::InvalidOracleConfig.into()));
+        assert_eq!(parse_switchboard_result(&AGGREGATOR_ACCOUNT[..300]), Err(LendingError::InvalidOracleConfig.into()));
+    }
+}
@@ -0,0 +1,113 @@
+use {
+    crate::{math::try_mul_div, oracle::normalize_price, LendingError, BPS_DENOMINATOR, WAD},
+    anchor_lang::prelude::{AnchorDeserialize, AnchorSerialize, ProgramError},
+};
+
+pub const PRICE_TWAP_SIZE : usize = 16+16+8;
+/// Slots over which a price is fully absorbed by the average, about ten minutes
+pub const TWAP_WINDOW_SLOTS : u64 = 1_500;
+
+/// Time weighted average of a reserve price, scaled by WAD so answers with different decimals mix
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, Default, Debug, PartialEq, Eq)]
+pub struct PriceTwap {
+    pub price_wads : u128,
+    /// spot price recorded by the last update, held until the next one
+    pub last_price_wads : u128,
+    pub last_update_slot : u64,
+}
+
+impl PriceTwap {
+    /// Fold the previous spot price into the average over the slots it was held, then record `price` as the new spot.
+    /// A price only weighs on the average once it has been standing for some slots
+    pub fn update(&mut self, price : u128, decimals : u8, current_slot : u64) -> Result<(), ProgramError> {
+        let spot_wads = normalize_price(price, decimals)?;
+        if self.price_wads == 0 {
+            self.price_wads = spot_wads;
+            self.last_price_wads = spot_wads;
+            self.last_update_slot = current_slot;
+            return Ok(());
+        }
+        let slots_elapsed = current_slot.checked_sub(self.last_update_slot).ok_or(LendingError::MathOverflow)?
+            .min(TWAP_WINDOW_SLOTS) as u128;
+        if self.last_price_wads > self.price_wads {
+            self.price_wads += try_mul_div(self.last_price_wads - self.price_wads, slots_elapsed, TWAP_WINDOW_SLOTS as u128)?;
+        } else {
+            self.price_wads -= try_mul_div(self.price_wads - self.last_price_wads, slots_elapsed, TWAP_WINDOW_SLOTS as u128)?;
+        }
+        self.last_price_wads = spot_wads;
+        self.last_update_slot = current_slot;
+        Ok(())
+    }
+
+    /// The lower of the spot and average prices, expressed in the decimals of the spot price
+    pub fn min_price(&self, price : u128, decimals : u8) -> Result<u128, ProgramError> {
+        Ok(price.min(self.price_in_decimals(decimals)?))
+    }
+
+    /// The higher of the spot and average prices, expressed in the decimals of the spot price
+    pub fn max_price(&self, price : u128, decimals : u8) -> Result<u128, ProgramError> {
+        Ok(price.max(self.price_in_decimals(decimals)?))
+    }
+
+    /// Whether `price` lies within `max_deviation_bps` of the average, always false while the average was never seeded
+    pub fn is_within(&self, price : u128, decimals : u8, max_deviation_bps : u64) -> Result<bool, ProgramError> {
+        if self.price_wads == 0 {
+            return Ok(false);
+        }
+        let spot_wads = normalize_price(price, decimals)?;
+        let deviation_bps = try_mul_div(spot_wads.max(self.price_wads) - spot_wads.min(self.price_wads), BPS_DENOMINATOR as u128, self.price_wads)?;
+        Ok(deviation_bps <= max_deviation_bps as u128)
+    }
+
+    fn price_in_decimals(&self, decimals : u8) -> Result<u128, ProgramError> {
+        let scale = 10u128.checked_pow(decimals as u32).ok_or(LendingError::MathOverflow)?;
+        Ok(self.price_wads.checked_mul(scale).ok_or(LendingError::MathOverflow)? / WAD)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn twap(price : u128) -> PriceTwap {
+        PriceTwap{ price_wads : price * WAD, last_price_wads : price * WAD, last_update_slot : 0 }
+    }
+
+    #[test]
+    fn first_update_seeds_average() {
+        let mut twap = PriceTwap::default();
+        twap.update(2_500, 2, 10).unwrap();
+        assert_eq!(twap.price_wads, 25 * WAD);
+        assert_eq!(twap.last_price_wads, 25 * WAD);
+        assert_eq!(twap.last_update_slot, 10);
+    }
+
+    #[test]
+    fn spike_moves_average_by_the_slots_it_was_held() {
+        let mut twap = twap(100);
+        twap.update(200, 0, TWAP_WINDOW_SLOTS / 10).unwrap();
+        assert_eq!(twap.price_wads, 100 * WAD);
+        twap.update(200, 0, TWAP_WINDOW_SLOTS / 5).unwrap();
+        assert_eq!(twap.price_wads, 110 * WAD);
+        assert_eq!(twap.min_price(200, 0), Ok(110));
+        assert_eq!(twap.max_price(20_000, 2), Ok(20_000));
+    }
+
+    #[test]
+    fn spike_after_a_long_gap_does_not_move_average() {
+        let mut twap = twap(100);
+        twap.update(1_000, 0, 10 * TWAP_WINDOW_SLOTS).unwrap();
+        assert_eq!(twap.price_wads, 100 * WAD);
+        // reverting in the next slot leaves only a single slot of the spike in the average
+        twap.update(100, 0, 10 * TWAP_WINDOW_SLOTS + 1).unwrap();
+        assert_eq!(twap.price_wads, 100 * WAD + 900 * WAD / TWAP_WINDOW_SLOTS as u128);
+    }
+
+    #[test]
+    fn deviation_bound_is_relative_to_average() {
+        let twap = twap(100);
+        assert_eq!(twap.is_within(110, 0, 1_000), Ok(true));
+        assert_eq!(twap.is_within(8_999, 2, 1_000), Ok(false));
+        assert_eq!(PriceTwap::default().is_within(100, 0, 1_000), Ok(false));
+    }
+}